@@ -0,0 +1,99 @@
+//! The arguments of the tools.
+//!
+//! `call_tool` deserializes the json arguments into these structs and `list_tools` advertises
+//! the input schema derived from them, so the two cannot drift apart.
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::date::Date;
+use crate::{MAX_FORECAST_DAYS, MAX_MARINE_DAYS};
+
+/// The arguments of the location based tools.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct LocationArguments {
+    /// The location, in any of the formats listed in the get_weather description.
+    pub location: String,
+    /// List the places an ambiguous city name matches instead of picking one.
+    pub disambiguate: Option<bool>,
+}
+
+/// The arguments of get_forecast.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ForecastArguments {
+    #[serde(flatten)]
+    pub place: LocationArguments,
+    /// The number of days to forecast, 1 when left out.
+    #[schemars(range(min = 1, max = MAX_FORECAST_DAYS))]
+    pub days: Option<i64>,
+    /// Restricts the hourly data to this hour of the day.
+    #[schemars(range(min = 0, max = 23))]
+    pub hour: Option<i64>,
+    /// Include the air quality.
+    pub aqi: Option<bool>,
+    /// Include the weather alerts.
+    pub alerts: Option<bool>,
+}
+
+/// The arguments of get_history.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct HistoryArguments {
+    #[serde(flatten)]
+    pub place: LocationArguments,
+    /// The first day, formatted yyyy-MM-dd.
+    pub dt: Date,
+    /// The last day, formatted yyyy-MM-dd; only dt when left out.
+    pub end_dt: Option<Date>,
+}
+
+/// The arguments of get_marine.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct MarineArguments {
+    #[serde(flatten)]
+    pub place: LocationArguments,
+    /// The number of days to forecast, 1 when left out.
+    #[schemars(range(min = 1, max = MAX_MARINE_DAYS))]
+    pub days: Option<i64>,
+}
+
+/// The arguments of get_astronomy.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AstronomyArguments {
+    #[serde(flatten)]
+    pub place: LocationArguments,
+    /// The day, formatted yyyy-MM-dd; today in UTC when left out.
+    pub dt: Option<Date>,
+}
+
+/// The arguments of search_locations.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SearchArguments {
+    /// A place name, postal code or any other location format to search for.
+    pub query: String,
+}
+
+/// The arguments of lookup_ip.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct IpArguments {
+    /// The IPv4 or IPv6 address; the caller's address when left out.
+    pub ip: Option<String>,
+}
+
+/// The arguments of get_air_quality.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AirQualityArguments {
+    #[serde(flatten)]
+    pub place: LocationArguments,
+    /// The number of days to forecast; only the current air quality when left out.
+    #[schemars(range(min = 1, max = MAX_FORECAST_DAYS))]
+    pub days: Option<i64>,
+}
+
+/// The arguments of get_future_weather.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct FutureArguments {
+    #[serde(flatten)]
+    pub place: LocationArguments,
+    /// The day, formatted yyyy-MM-dd, between 14 and 300 days from today.
+    pub dt: Date,
+}
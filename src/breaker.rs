@@ -0,0 +1,147 @@
+//! The circuit breaker that fails weather api calls fast while the api is down.
+//!
+//! After a number of consecutive failed calls the circuit opens and calls fail at once for a
+//! cool-down period. The first call after the cool-down goes through as a single probe, which
+//! closes the circuit when it succeeds and opens it again when it fails. The breaker lives as
+//! long as the component instance.
+
+use std::sync::{Mutex, MutexGuard};
+
+use serde::Serialize;
+
+/// The consecutive failed calls that open the circuit, unless configured otherwise.
+pub const FAILURE_THRESHOLD: u64 = 5;
+/// How long an open circuit fails calls before it lets a probe through, unless configured otherwise.
+pub const COOL_DOWN_MS: u64 = 30_000;
+
+static CIRCUIT_BREAKER: Mutex<CircuitBreaker> = Mutex::new(CircuitBreaker {
+    failure_threshold: FAILURE_THRESHOLD,
+    cool_down_ms: COOL_DOWN_MS,
+    state: State::Closed,
+    consecutive_failures: 0,
+    opened_at_ns: 0,
+    last_failure: None,
+    calls: 0,
+    failures: 0,
+    rejected: 0,
+});
+
+/// Returns the circuit breaker of this component instance.
+pub fn circuit_breaker() -> MutexGuard<'static, CircuitBreaker> {
+    crate::lock_instance_state(&CIRCUIT_BREAKER)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Calls go through.
+    Closed,
+    /// Calls fail at once until the cool-down is over.
+    Open,
+    /// A single probe call is on its way.
+    HalfOpen,
+}
+
+/// How a call the circuit breaker admitted should be made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permit {
+    Call,
+    /// The call probes an api that was down, so it should not be retried.
+    Probe,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u64,
+    cool_down_ms: u64,
+    state: State,
+    consecutive_failures: u64,
+    opened_at_ns: u64,
+    last_failure: Option<String>,
+    calls: u64,
+    failures: u64,
+    rejected: u64,
+}
+
+impl CircuitBreaker {
+    /// Applies the configured threshold and cool-down to the following calls.
+    pub fn configure(&mut self, failure_threshold: u64, cool_down_ms: u64) {
+        self.failure_threshold = failure_threshold.max(1);
+        self.cool_down_ms = cool_down_ms;
+    }
+
+    /// Admits a call at `now_ns` on the monotonic clock, or returns the milliseconds until the next probe.
+    pub fn admit(&mut self, now_ns: u64) -> Result<Permit, u64> {
+        match self.state {
+            State::Closed => {
+                self.calls += 1;
+                Ok(Permit::Call)
+            }
+            State::Open if self.remaining_ms(now_ns) > 0 => {
+                self.rejected += 1;
+                Err(self.remaining_ms(now_ns))
+            }
+            // A half open circuit only sees another call when the probe never reported back
+            State::Open | State::HalfOpen => {
+                self.state = State::HalfOpen;
+                self.calls += 1;
+                Ok(Permit::Probe)
+            }
+        }
+    }
+
+    /// Records that the api answered, even if only to reject the request.
+    pub fn record_success(&mut self) {
+        self.state = State::Closed;
+        self.consecutive_failures = 0;
+    }
+
+    /// Records that the api could not be reached or failed, opening the circuit when it failed too often.
+    pub fn record_failure(&mut self, now_ns: u64, failure: String) {
+        self.consecutive_failures += 1;
+        self.failures += 1;
+        self.last_failure = Some(failure);
+        if self.state == State::HalfOpen || self.consecutive_failures >= self.failure_threshold {
+            self.state = State::Open;
+            self.opened_at_ns = now_ns;
+        }
+    }
+
+    /// Returns the status served by the diagnostics resource.
+    pub fn status(&self, now_ns: u64) -> Status {
+        Status {
+            state: self.state,
+            consecutive_failures: self.consecutive_failures,
+            failure_threshold: self.failure_threshold,
+            cool_down_ms: self.cool_down_ms,
+            retry_after_seconds: (self.state == State::Open).then(|| self.remaining_ms(now_ns).div_ceil(1000)),
+            last_failure: self.last_failure.clone(),
+            calls: self.calls,
+            failures: self.failures,
+            rejected: self.rejected,
+        }
+    }
+
+    fn remaining_ms(&self, now_ns: u64) -> u64 {
+        self.cool_down_ms.saturating_sub(now_ns.saturating_sub(self.opened_at_ns) / 1_000_000)
+    }
+}
+
+/// The status of the circuit breaker around the weather api calls.
+#[derive(Debug, Clone, Serialize)]
+pub struct Status {
+    pub state: State,
+    pub consecutive_failures: u64,
+    pub failure_threshold: u64,
+    pub cool_down_ms: u64,
+    /// The seconds until the next probe while the circuit is open.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after_seconds: Option<u64>,
+    pub last_failure: Option<String>,
+    /// The calls let through since the component was instantiated.
+    pub calls: u64,
+    /// The calls let through that failed.
+    pub failures: u64,
+    /// The calls that failed fast because the circuit was open.
+    pub rejected: u64,
+}
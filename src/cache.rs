@@ -0,0 +1,442 @@
+//! The in-memory cache of weather api responses.
+//!
+//! Successful responses are kept per endpoint and canonical query for as long as the data of
+//! their endpoint stays fresh, so asking about the same place again does not spend api quota.
+//! The cache lives as long as the component instance. Built with the `keyvalue` feature, the
+//! responses are also kept in the host's wasi:keyvalue store, so they outlive the instance.
+//!
+//! Expired responses are kept a while longer: when the api fails, a stale response that expired
+//! at most the endpoint's `StalePolicy` ago is served instead of the error. For the endpoints the
+//! policy lists as revalidating, it is served at once while a fresh response is fetched for later calls.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::date::Date;
+
+/// The cache holds at most this many responses, dropping the oldest ones first.
+pub const MAX_ENTRIES: usize = 256;
+
+/// The nanoseconds in a second, to move between the monotonic and the wall clock.
+#[cfg(feature = "keyvalue")]
+const NS_PER_SECOND: u64 = 1_000_000_000;
+
+static RESPONSE_CACHE: Mutex<ResponseCache> = Mutex::new(ResponseCache { entries: BTreeMap::new(), hits: Vec::new() });
+
+/// Returns the response cache of this component instance.
+pub fn response_cache() -> MutexGuard<'static, ResponseCache> {
+    crate::lock_instance_state(&RESPONSE_CACHE)
+}
+
+/// How long the responses of an endpoint stay fresh.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// The response is about the moment it was fetched, such as a local time.
+    Uncached,
+    For { ms: u64 },
+    /// The response describes days that are over everywhere and never changes.
+    Forever,
+}
+
+/// Returns how long the responses of `endpoint` to `query` stay fresh when fetched at `now_seconds`.
+pub fn freshness(endpoint: &str, query: &[(&str, String)], now_seconds: u64) -> Freshness {
+    const MINUTE: u64 = 60_000;
+    match endpoint {
+        "/v1/current.json" | "/v1/alerts.json" => Freshness::For { ms: 5 * MINUTE },
+        "/v1/forecast.json" | "/v1/marine.json" => Freshness::For { ms: 30 * MINUTE },
+        "/v1/astronomy.json" | "/v1/sports.json" => Freshness::For { ms: 60 * MINUTE },
+        "/v1/future.json" => Freshness::For { ms: 6 * 60 * MINUTE },
+        "/v1/search.json" => Freshness::For { ms: 24 * 60 * MINUTE },
+        // The last day asked for can still be under way somewhere, at UTC-12 until noon UTC the next day
+        "/v1/history.json" => {
+            let value = |name: &str| query.iter().find(|(n, _)| *n == name).and_then(|(_, value)| Date::parse(value));
+            let ended = Date::from_unix_seconds(now_seconds.saturating_sub(12 * 60 * 60));
+            match value("end_dt").or_else(|| value("dt")) {
+                Some(last) if last < ended => Freshness::Forever,
+                _ => Freshness::For { ms: 5 * MINUTE },
+            }
+        }
+        _ => Freshness::Uncached,
+    }
+}
+
+/// The endpoints whose stale responses can be served, by the name the stale policy lists them with.
+pub const STALE_ENDPOINTS: [&str; 8] = ["current", "alerts", "forecast", "marine", "astronomy", "sports", "future", "search"];
+
+/// Returns how long after they expire the responses of `endpoint` may be served while the api fails,
+/// unless the stale policy configures otherwise.
+pub fn max_stale_ms(endpoint: &str) -> u64 {
+    const HOUR: u64 = 60 * 60_000;
+    match endpoint {
+        "/v1/current.json" | "/v1/alerts.json" => HOUR,
+        "/v1/forecast.json" | "/v1/marine.json" | "/v1/astronomy.json" | "/v1/sports.json" => 6 * HOUR,
+        "/v1/future.json" | "/v1/search.json" => 7 * 24 * HOUR,
+        _ => 0,
+    }
+}
+
+/// How long after they expire the responses of each endpoint may be served while the api fails,
+/// and which endpoints serve them at once while a fresh response is fetched.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StalePolicy {
+    /// The configured seconds by endpoint name, overriding `max_stale_ms`.
+    max_stale_seconds: BTreeMap<String, u64>,
+    /// The names of the endpoints that serve stale while revalidating.
+    revalidate: BTreeSet<String>,
+}
+
+impl StalePolicy {
+    /// Parses a comma separated list of `<endpoint>=<seconds>`, such as `current=1200,forecast=0`.
+    ///
+    /// The error completes a sentence about the configuration the policy was read from.
+    pub fn parse(text: &str) -> Result<StalePolicy, String> {
+        let mut max_stale_seconds = BTreeMap::new();
+        for item in text.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let Some((name, seconds)) = item.split_once('=') else {
+                return Err(format!("must list <endpoint>=<seconds> items such as current=1200, not {}", item));
+            };
+            let name = name.trim();
+            if !STALE_ENDPOINTS.contains(&name) {
+                return Err(format!("names an unknown endpoint {}; use one of {}", name, STALE_ENDPOINTS.join(", ")));
+            }
+            let seconds = seconds.trim().parse()
+                .map_err(|_| format!("must give {} a whole number of seconds, not {}", name, seconds.trim()))?;
+            max_stale_seconds.insert(name.to_string(), seconds);
+        }
+        Ok(StalePolicy { max_stale_seconds, revalidate: BTreeSet::new() })
+    }
+
+    /// Adds a comma separated list of endpoints that serve stale while revalidating, such as `current,forecast`.
+    ///
+    /// The error completes a sentence about the configuration the list was read from.
+    pub fn revalidating(mut self, text: &str) -> Result<StalePolicy, String> {
+        for name in text.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            if !STALE_ENDPOINTS.contains(&name) {
+                return Err(format!("names an unknown endpoint {}; use one of {}", name, STALE_ENDPOINTS.join(", ")));
+            }
+            self.revalidate.insert(name.to_string());
+        }
+        Ok(self)
+    }
+
+    /// Tells whether an expired response of `endpoint` is served at once while a fresh one is fetched,
+    /// instead of only when fetching a fresh one fails.
+    pub fn revalidates(&self, endpoint: &str) -> bool {
+        self.revalidate.contains(endpoint.trim_start_matches("/v1/").trim_end_matches(".json"))
+    }
+
+    /// Returns how long after they expire the responses of `endpoint` may be served while the api fails.
+    pub fn max_stale_ms(&self, endpoint: &str) -> u64 {
+        let name = endpoint.trim_start_matches("/v1/").trim_end_matches(".json");
+        match self.max_stale_seconds.get(name) {
+            Some(seconds) => seconds.saturating_mul(1000),
+            None => max_stale_ms(endpoint),
+        }
+    }
+}
+
+/// Returns the cache key of a request, whose location is already in its canonical form.
+pub fn cache_key(endpoint: &str, query: &[(&str, String)]) -> String {
+    let mut key = endpoint.to_string();
+    for (name, value) in query {
+        key.push_str(&format!("&{}={}", name, value.trim()));
+    }
+    key
+}
+
+#[derive(Debug)]
+struct Entry {
+    body: String,
+    /// When the response was fetched, on the monotonic clock.
+    fetched_at_ns: u64,
+    /// When the response was fetched, in seconds since 1970-01-01T00:00:00Z.
+    fetched_at_seconds: u64,
+    expires_at_ns: Option<u64>,
+    /// Until when the expired response is kept to be served stale.
+    kept_until_ns: Option<u64>,
+}
+
+impl Entry {
+    fn is_fresh(&self, now_ns: u64) -> bool {
+        self.expires_at_ns.is_none_or(|expires_at_ns| now_ns < expires_at_ns)
+    }
+
+    fn is_kept(&self, now_ns: u64) -> bool {
+        self.kept_until_ns.is_none_or(|kept_until_ns| now_ns < kept_until_ns)
+    }
+}
+
+/// A response served from the cache during the current tool call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hit {
+    /// When the response was fetched, in seconds since 1970-01-01T00:00:00Z.
+    pub fetched_at_seconds: u64,
+    /// Why the expired response was served, when it was.
+    pub stale: Option<Stale>,
+}
+
+/// Why an expired response was served.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stale {
+    /// Fetching a fresh response failed with this message.
+    Failed(String),
+    /// A fresh response is being fetched, for the calls after this one.
+    Revalidating,
+}
+
+#[derive(Debug)]
+pub struct ResponseCache {
+    entries: BTreeMap<String, Entry>,
+    /// The responses served from the cache during the current tool call.
+    hits: Vec<Hit>,
+}
+
+impl ResponseCache {
+    /// Forgets the cache hits recorded so far, as the result is not built from those responses.
+    pub fn forget_hits(&mut self) {
+        self.hits.clear();
+    }
+
+    /// Returns the responses served from the cache during the current tool call.
+    pub fn hits(&self) -> &[Hit] {
+        &self.hits
+    }
+
+    /// Returns the fresh cached response for `key` at `now_ns` on the monotonic clock and at `now_seconds`.
+    pub fn get(&mut self, key: &str, now_ns: u64, now_seconds: u64) -> Option<String> {
+        let entry = self.entry(key, now_ns, now_seconds)?;
+        if !entry.is_fresh(now_ns) {
+            return None;
+        }
+        let hit = Hit { fetched_at_seconds: entry.fetched_at_seconds, stale: None };
+        let body = entry.body.clone();
+        self.hits.push(hit);
+        Some(body)
+    }
+
+    /// Tells whether an expired response for `key` that can still be served stale is at hand.
+    pub fn has_stale(&self, key: &str, now_ns: u64, max_stale_ms: u64) -> bool {
+        self.stale_entry(key, now_ns, max_stale_ms).is_some()
+    }
+
+    /// Returns the response for `key` that expired at most `max_stale_ms` ago, served as it is `stale`.
+    pub fn get_stale(&mut self, key: &str, now_ns: u64, max_stale_ms: u64, stale: Stale) -> Option<String> {
+        let entry = self.stale_entry(key, now_ns, max_stale_ms)?;
+        let hit = Hit { fetched_at_seconds: entry.fetched_at_seconds, stale: Some(stale) };
+        let body = entry.body.clone();
+        self.hits.push(hit);
+        Some(body)
+    }
+
+    /// Caches a response that stays fresh for `freshness`, fetched at `now_ns` on the monotonic clock
+    /// and at `now_seconds`, keeping it `max_stale_ms` after it expires.
+    pub fn insert(&mut self, freshness: Freshness, key: String, body: &str, now_ns: u64, now_seconds: u64, max_stale_ms: u64) {
+        let fresh_ms = match freshness {
+            Freshness::Uncached => return,
+            Freshness::For { ms } => Some(ms),
+            Freshness::Forever => None,
+        };
+        #[cfg(feature = "keyvalue")]
+        persistent::save(&key, &persistent::Stored {
+            body: body.to_string(),
+            fetched_at_seconds: now_seconds,
+            expires_at_seconds: fresh_ms.map(|ms| now_seconds.saturating_add(ms.div_ceil(1000))),
+            kept_until_seconds: fresh_ms.map(|ms| now_seconds.saturating_add(ms.saturating_add(max_stale_ms).div_ceil(1000))),
+        });
+        self.remember(key, Entry {
+            body: body.to_string(),
+            fetched_at_ns: now_ns,
+            fetched_at_seconds: now_seconds,
+            expires_at_ns: fresh_ms.map(|ms| now_ns.saturating_add(ms.saturating_mul(1_000_000))),
+            kept_until_ns: fresh_ms.map(|ms| now_ns.saturating_add(ms.saturating_add(max_stale_ms).saturating_mul(1_000_000))),
+        }, now_ns);
+    }
+
+    /// Returns the kept entry for `key`, loading it from the keyvalue store when it is not in memory.
+    #[cfg_attr(not(feature = "keyvalue"), allow(unused_variables))]
+    fn entry(&mut self, key: &str, now_ns: u64, now_seconds: u64) -> Option<&Entry> {
+        if self.entries.get(key).is_some_and(|entry| !entry.is_kept(now_ns)) {
+            self.entries.remove(key);
+        }
+
+        #[cfg(feature = "keyvalue")]
+        if !self.entries.contains_key(key) {
+            if let Some(stored) = persistent::load(key, now_seconds) {
+                // The monotonic clock of another instance may have started elsewhere, so the times are stored in wall clock time
+                let at_ns = |seconds: u64| match seconds.checked_sub(now_seconds) {
+                    Some(ahead) => now_ns.saturating_add(ahead.saturating_mul(NS_PER_SECOND)),
+                    None => now_ns.saturating_sub((now_seconds - seconds).saturating_mul(NS_PER_SECOND)),
+                };
+                self.remember(key.to_string(), Entry {
+                    body: stored.body,
+                    fetched_at_ns: at_ns(stored.fetched_at_seconds),
+                    fetched_at_seconds: stored.fetched_at_seconds,
+                    expires_at_ns: stored.expires_at_seconds.map(at_ns),
+                    kept_until_ns: stored.kept_until_seconds.map(at_ns),
+                }, now_ns);
+            }
+        }
+        self.entries.get(key)
+    }
+
+    fn stale_entry(&self, key: &str, now_ns: u64, max_stale_ms: u64) -> Option<&Entry> {
+        let entry = self.entries.get(key)?;
+        let stale_until_ns = entry.expires_at_ns?.saturating_add(max_stale_ms.saturating_mul(1_000_000));
+        (!entry.is_fresh(now_ns) && entry.is_kept(now_ns) && now_ns < stale_until_ns).then_some(entry)
+    }
+
+    /// Keeps an entry in memory, making room by dropping the entries past keeping and then the oldest entries.
+    fn remember(&mut self, key: String, entry: Entry, now_ns: u64) {
+        self.entries.retain(|_, entry| entry.is_kept(now_ns));
+        while self.entries.len() >= MAX_ENTRIES {
+            let Some(oldest) = self.entries.iter().min_by_key(|(_, entry)| entry.fetched_at_ns).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+        self.entries.insert(key, entry);
+    }
+}
+
+/// The cached responses in the host's wasi:keyvalue store.
+///
+/// The store is a best effort: when it fails the response is fetched or only kept in memory.
+/// An index of the stored responses bounds them like the in-memory cache, as the host keeps
+/// a response until it is deleted.
+#[cfg(feature = "keyvalue")]
+mod persistent {
+    use std::collections::BTreeMap;
+
+    use serde::{Deserialize, Serialize};
+
+    use super::MAX_ENTRIES;
+    use crate::bindings::wasi::keyvalue::store::{open, Bucket};
+    use crate::bindings::wasi::logging::logging;
+
+    /// The keys of the cached responses start with this, as they share the default bucket.
+    const KEY_PREFIX: &str = "weather-api/";
+    /// The key of the index, which no cached response has as their keys continue with an endpoint.
+    const INDEX_KEY: &str = "weather-api/index";
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Stored {
+        pub body: String,
+        pub fetched_at_seconds: u64,
+        /// None when the response never expires.
+        pub expires_at_seconds: Option<u64>,
+        /// None when the response is kept forever.
+        pub kept_until_seconds: Option<u64>,
+    }
+
+    /// When a stored response was fetched and until when it is kept, by its key in the store.
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Indexed {
+        fetched_at_seconds: u64,
+        kept_until_seconds: Option<u64>,
+    }
+
+    /// Returns the stored response for `key` at `now_seconds`, deleting it when it is past keeping.
+    pub fn load(key: &str, now_seconds: u64) -> Option<Stored> {
+        let bucket = bucket()?;
+        let key = format!("{}{}", KEY_PREFIX, key);
+        let value = bucket.get(&key)
+            .map_err(|e| warn(&format!("Could not read {} from the keyvalue store: {:?}", key, e)))
+            .ok()??;
+        match serde_json::from_slice::<Stored>(&value) {
+            Ok(stored) if stored.kept_until_seconds.is_none_or(|kept_until_seconds| now_seconds < kept_until_seconds) => Some(stored),
+            _ => {
+                delete(&bucket, &key);
+                None
+            }
+        }
+    }
+
+    /// Stores a response and adds it to the index, deleting the responses past keeping and then
+    /// the oldest ones beyond `MAX_ENTRIES`.
+    pub fn save(key: &str, stored: &Stored) {
+        let Some(bucket) = bucket() else {
+            return;
+        };
+        let key = format!("{}{}", KEY_PREFIX, key);
+        let value = match serde_json::to_vec(stored) {
+            Ok(value) => value,
+            Err(e) => return warn(&format!("Could not serialize {}: {}", key, e)),
+        };
+        // Read first, as without an index every stored response is deleted
+        let Some(mut index) = read_index(&bucket) else {
+            return;
+        };
+        if let Err(e) = bucket.set(&key, &value) {
+            return warn(&format!("Could not write {} to the keyvalue store: {:?}", key, e));
+        }
+
+        let now_seconds = stored.fetched_at_seconds;
+        index.insert(key, Indexed { fetched_at_seconds: now_seconds, kept_until_seconds: stored.kept_until_seconds });
+        let mut evicted: Vec<String> = index.iter()
+            .filter(|(_, indexed)| indexed.kept_until_seconds.is_some_and(|kept_until_seconds| kept_until_seconds <= now_seconds))
+            .map(|(key, _)| key.clone())
+            .collect();
+        index.retain(|key, _| !evicted.contains(key));
+        while index.len() > MAX_ENTRIES {
+            let Some(oldest) = index.iter().min_by_key(|(_, indexed)| indexed.fetched_at_seconds).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            index.remove(&oldest);
+            evicted.push(oldest);
+        }
+        for key in &evicted {
+            delete(&bucket, key);
+        }
+        match serde_json::to_vec(&index) {
+            Ok(value) => if let Err(e) = bucket.set(INDEX_KEY, &value) {
+                warn(&format!("Could not write {} to the keyvalue store: {:?}", INDEX_KEY, e));
+            },
+            Err(e) => warn(&format!("Could not serialize {}: {}", INDEX_KEY, e)),
+        }
+    }
+
+    /// Reads the index of the stored responses, or None when the store fails.
+    ///
+    /// Without an index the stored responses cannot be bounded, so a missing or corrupt index
+    /// deletes them all and starts over.
+    fn read_index(bucket: &Bucket) -> Option<BTreeMap<String, Indexed>> {
+        let value = bucket.get(INDEX_KEY)
+            .map_err(|e| warn(&format!("Could not read {} from the keyvalue store: {:?}", INDEX_KEY, e)))
+            .ok()?;
+        if let Some(index) = value.and_then(|value| serde_json::from_slice(&value).ok()) {
+            return Some(index);
+        }
+        let mut cursor = None;
+        loop {
+            let response = match bucket.list_keys(cursor) {
+                Ok(response) => response,
+                Err(e) => {
+                    warn(&format!("Could not list the keys of the keyvalue store: {:?}", e));
+                    break;
+                }
+            };
+            for key in response.keys.iter().filter(|key| key.starts_with(KEY_PREFIX) && *key != INDEX_KEY) {
+                delete(bucket, key);
+            }
+            match response.cursor {
+                Some(next) if !response.keys.is_empty() => cursor = Some(next),
+                _ => break,
+            }
+        }
+        Some(BTreeMap::new())
+    }
+
+    fn bucket() -> Option<Bucket> {
+        open("").map_err(|e| warn(&format!("Could not open the keyvalue store: {:?}", e))).ok()
+    }
+
+    fn delete(bucket: &Bucket, key: &str) {
+        if let Err(e) = bucket.delete(key) {
+            warn(&format!("Could not delete {} from the keyvalue store: {:?}", key, e));
+        }
+    }
+
+    fn warn(message: &str) {
+        logging::log(logging::Level::Warn, "cache", message);
+    }
+}
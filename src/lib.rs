@@ -1,20 +1,95 @@
 
 const WEATHER_API_KEY: &str = "WEATHER_API_KEY";
+/// The optional secret that overrides `MAX_BODY_BYTES`.
+const WEATHER_API_MAX_BODY_BYTES: &str = "WEATHER_API_MAX_BODY_BYTES";
+/// A weather api response body larger than this many bytes is rejected instead of read into memory.
+const MAX_BODY_BYTES: u64 = 8 * 1024 * 1024;
+/// The optional secret that overrides `CONNECT_TIMEOUT_MS`.
+const WEATHER_API_CONNECT_TIMEOUT_MS: &str = "WEATHER_API_CONNECT_TIMEOUT_MS";
+/// The optional secret that overrides `FIRST_BYTE_TIMEOUT_MS`.
+const WEATHER_API_FIRST_BYTE_TIMEOUT_MS: &str = "WEATHER_API_FIRST_BYTE_TIMEOUT_MS";
+/// The optional secret that overrides `BETWEEN_BYTES_TIMEOUT_MS`.
+const WEATHER_API_BETWEEN_BYTES_TIMEOUT_MS: &str = "WEATHER_API_BETWEEN_BYTES_TIMEOUT_MS";
+/// The milliseconds to wait for the connection to the weather api.
+const CONNECT_TIMEOUT_MS: u64 = 5_000;
+/// The milliseconds to wait for the weather api to start its response.
+const FIRST_BYTE_TIMEOUT_MS: u64 = 20_000;
+/// The milliseconds to wait between the chunks of a weather api response body.
+const BETWEEN_BYTES_TIMEOUT_MS: u64 = 10_000;
+/// The optional secret that overrides the circuit breaker's `FAILURE_THRESHOLD`.
+const WEATHER_API_FAILURE_THRESHOLD: &str = "WEATHER_API_FAILURE_THRESHOLD";
+/// The optional secret that overrides the circuit breaker's `COOL_DOWN_MS`.
+const WEATHER_API_COOL_DOWN_MS: &str = "WEATHER_API_COOL_DOWN_MS";
+/// A request failing with a connection error, 429 or 5xx is tried at most this many times.
+const MAX_ATTEMPTS: u32 = 3;
+/// The milliseconds of backoff before the first retry, doubled for every further retry.
+const BASE_BACKOFF_MS: u64 = 500;
+/// The backoff between retries never grows beyond this many milliseconds.
+const MAX_BACKOFF_MS: u64 = 4_000;
+/// A `Retry-After` longer than this is not waited for; the failure is returned instead.
+const MAX_RETRY_AFTER_MS: u64 = 10_000;
+/// The optional secret holding the stale policy, which overrides `cache::max_stale_ms` per endpoint.
+const WEATHER_API_MAX_STALE_SECONDS: &str = "WEATHER_API_MAX_STALE_SECONDS";
+/// The optional secret listing the endpoints that serve a stale response at once and fetch a fresh one for later calls.
+const WEATHER_API_STALE_WHILE_REVALIDATE: &str = "WEATHER_API_STALE_WHILE_REVALIDATE";
+/// The optional secret that overrides `location::COORDINATE_DECIMALS`.
+const WEATHER_API_COORDINATE_DECIMALS: &str = "WEATHER_API_COORDINATE_DECIMALS";
+/// The weatherapi.com error code of an api key that exceeded its monthly call quota.
+const QUOTA_EXCEEDED: i64 = 2007;
+/// The uri of the resource reporting the circuit breaker status.
+const DIAGNOSTICS_URI: &str = "weather-api://diagnostics";
+/// weatherapi.com serves at most this many days of history in one range.
+const MAX_HISTORY_DAYS: i64 = 30;
+/// The forecast covers at most this many days.
+const MAX_FORECAST_DAYS: i64 = 14;
+/// The marine forecast covers at most this many days.
+const MAX_MARINE_DAYS: i64 = 7;
+/// The future weather starts at the date this many days ahead.
+const MIN_FUTURE_DAYS: i64 = 14;
+/// The future weather ends at the date this many days ahead.
+const MAX_FUTURE_DAYS: i64 = 300;
+
+mod arguments;
+mod breaker;
+mod cache;
+mod date;
+mod location;
+mod models;
+mod schemas;
 mod bindings {
     use crate::WeatherAPIRouter;
+    #[cfg(not(feature = "keyvalue"))]
     wit_bindgen::generate!({ 
         generate_all,
         world: "mcp-secrets"
      });
+    #[cfg(feature = "keyvalue")]
+    wit_bindgen::generate!({
+        generate_all,
+        world: "mcp-secrets-keyvalue"
+     });
 
 
     export!(WeatherAPIRouter);
 }
 
-use bindings::exports::wasix::mcp::{router::{self, Annotations, CallToolResult, Content::{self, Text}, GetPromptResult, Guest, McpResource, Prompt, PromptError, PromptMessage, PromptMessageContent, PromptMessageRole, ReadResourceResult, ResourceContents, ResourceError, Role, ServerCapabilities, TextContent, TextResourceContents, Tool, ToolError, Value}, secrets_list::{self, SecretsDescription}};
-use bindings::wasix::mcp::secrets_store::{get, reveal};
-use bindings::wasi::http::{outgoing_handler::handle,types::{Scheme,Fields,OutgoingRequest}};
+use bindings::exports::wasix::mcp::{router::{self, Annotations, CallToolResult, Content::Text, GetPromptResult, Guest, McpResource, Prompt, PromptError, PromptMessage, PromptMessageContent, PromptMessageRole, ReadResourceResult, ResourceContents, ResourceError, Role, ServerCapabilities, TextContent, TextResourceContents, Tool, ToolError, Value}, secrets_list::{self, SecretsDescription}};
+use bindings::wasix::mcp::secrets_store::{get, reveal, SecretsError};
+use bindings::wasi::http::{outgoing_handler::handle,types::{http_error_code,ErrorCode,Fields,FutureIncomingResponse,IncomingBody,IncomingResponse,OutgoingRequest,RequestOptions,Scheme}};
+use bindings::wasi::io::streams::StreamError;
 use bindings::wasi::logging::logging;
+use bindings::wasi::clocks::{monotonic_clock, wall_clock};
+use bindings::wasi::random::random;
+use arguments::{AirQualityArguments, AstronomyArguments, FutureArguments, ForecastArguments, HistoryArguments, IpArguments, LocationArguments, MarineArguments, SearchArguments};
+use breaker::{circuit_breaker, Permit, COOL_DOWN_MS, FAILURE_THRESHOLD};
+use cache::{cache_key, freshness, response_cache, Freshness, Stale, StalePolicy, STALE_ENDPOINTS};
+use date::{format_timestamp, parse_http_date, Date};
+use location::{Place, COORDINATE_DECIMALS, MAX_COORDINATE_DECIMALS};
+use models::{AirQualityReport, AirQualityResponse, AlertsReport, AlertsResponse, Astronomy, AstronomyResponse, CurrentWeather, ErrorResponse, Forecast, ForecastDay, ForecastResponse, IpLookup, Location, Marine, MarineResponse, Search, SearchLocation, SportsEvents, TimezoneResponse};
+use schemas::{check_arguments, input_schema, output_schema};
+use schemars::JsonSchema;
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use urlencoding::encode;
 
 struct WeatherAPIRouter;
@@ -25,7 +100,46 @@ impl secrets_list::Guest for WeatherAPIRouter {
        vec![SecretsDescription{ 
         name: WEATHER_API_KEY.to_string(), 
         description: "the api key for weatherapi.com".to_string(), 
-        required: true }]
+        required: true },
+        SecretsDescription{
+        name: WEATHER_API_MAX_BODY_BYTES.to_string(),
+        description: format!("the maximum size in bytes of a weatherapi.com response, {} when not set", MAX_BODY_BYTES),
+        required: false },
+        SecretsDescription{
+        name: WEATHER_API_CONNECT_TIMEOUT_MS.to_string(),
+        description: format!("the milliseconds to wait for a connection to weatherapi.com, {} when not set", CONNECT_TIMEOUT_MS),
+        required: false },
+        SecretsDescription{
+        name: WEATHER_API_FIRST_BYTE_TIMEOUT_MS.to_string(),
+        description: format!("the milliseconds to wait for weatherapi.com to start responding, {} when not set", FIRST_BYTE_TIMEOUT_MS),
+        required: false },
+        SecretsDescription{
+        name: WEATHER_API_BETWEEN_BYTES_TIMEOUT_MS.to_string(),
+        description: format!("the milliseconds to wait between the parts of a weatherapi.com response, {} when not set", BETWEEN_BYTES_TIMEOUT_MS),
+        required: false },
+        SecretsDescription{
+        name: WEATHER_API_FAILURE_THRESHOLD.to_string(),
+        description: format!("the consecutive failed weatherapi.com calls after which calls fail fast, {} when not set", FAILURE_THRESHOLD),
+        required: false },
+        SecretsDescription{
+        name: WEATHER_API_COOL_DOWN_MS.to_string(),
+        description: format!("the milliseconds calls fail fast before weatherapi.com is tried again, {} when not set", COOL_DOWN_MS),
+        required: false },
+        SecretsDescription{
+        name: WEATHER_API_MAX_STALE_SECONDS.to_string(),
+        description: format!("how long after it expires a cached response is served when weatherapi.com fails, as <endpoint>=<seconds> items such as current=1200,forecast=0 for the endpoints {}; one hour for current and alerts, six hours for forecast, marine, astronomy and sports and a week for future and search when not set",
+            STALE_ENDPOINTS.join(", ")),
+        required: false },
+        SecretsDescription{
+        name: WEATHER_API_STALE_WHILE_REVALIDATE.to_string(),
+        description: format!("the endpoints, such as current,forecast, whose expired cached responses are served at once while a fresh one is fetched for later calls, instead of only when weatherapi.com fails; one of {} each, none when not set",
+            STALE_ENDPOINTS.join(", ")),
+        required: false },
+        SecretsDescription{
+        name: WEATHER_API_COORDINATE_DECIMALS.to_string(),
+        description: format!("the decimals latitudes and longitudes are rounded to, so nearby coordinates share cached responses, {} (about a kilometre) when not set and at most {}",
+            COORDINATE_DECIMALS, MAX_COORDINATE_DECIMALS),
+        required: false }]
     }
 }
 
@@ -37,9 +151,14 @@ impl Guest for WeatherAPIRouter{
     }
 
     fn instructions() -> String {
-        "Fetches the current weather 
+        "Fetches weather data from weatherapi.com 
         for a given location. 
-        Call the get_weather tool and pass a json {'location'='input your location here'}, 
+        Call the get_weather tool for the current weather, get_forecast for the coming days,
+        get_future_weather for 14 to 300 days ahead, get_history for past days, get_marine for the sea,
+        get_astronomy for the sun and moon, get_alerts for weather alerts, get_air_quality for pollutants
+        and pollen, get_sports_events for upcoming events, get_timezone for the local time and
+        search_locations to find a place or lookup_ip to locate an IP address.
+        Pass a json such as {'location'='input your location here'}, 
         as input. Location can be in different formats:
         * Latitude and Longitude (Decimal degree) e.g: location=48.8567,2.3508
         * city name e.g.: location=Paris
@@ -50,13 +169,17 @@ impl Guest for WeatherAPIRouter{
         * iata:<3 digit airport code> e.g: location=iata:DXB
         * auto:ip IP lookup e.g: location=auto:ip
         * IP address (IPv4 and IPv6 supported) e.g: location=100.0.0.1
-        * By ID returned from Search API. e.g: location=id:2801268".to_string()
+        * By ID returned from the search_locations tool. e.g: location=id:2801268".to_string()
     }
 
     fn capabilities() -> ServerCapabilities {
         ServerCapabilities {
             prompts: None,
-            resources: None,
+            // The diagnostics resource lists the circuit breaker status
+            resources: Some(router::ResourcesCapability {
+                subscribe: Some(false),
+                list_changed: Some(false),
+            }),
             tools: Some(router::ToolsCapability {
                 list_changed: Some(true),
             }),
@@ -81,181 +204,360 @@ impl Guest for WeatherAPIRouter{
                 * iata:<3 digit airport code> e.g: location=iata:DXB
                 * auto:ip IP lookup e.g: location=auto:ip
                 * IP address (IPv4 and IPv6 supported) e.g: location=100.0.0.1
-                * By ID returned from Search API. e.g: location=id:2801268".to_string(),
+                * By ID returned from the search_locations tool. e.g: location=id:2801268
+                Pass disambiguate=true to get the list of matching places, with their ids,
+                instead of the weather when a city name matches several places.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<LocationArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<CurrentWeather>(),
+                }),
+            },
+            Tool {
+                name: "get_forecast".to_string(),
+                description: "Fetches the weather forecast for up to 14 days ahead for a
+                specific location, with daily, astronomical and hourly data per day.
+                The location and disambiguate parameters work as in get_weather.
+                Optionally pass days (1-14, default 1), hour (0-23) to restrict the hourly
+                data to a single hour, and aqi and alerts (true/false) to include air
+                quality data and weather alerts.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<ForecastArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<Forecast>(),
+                }),
+            },
+            Tool {
+                name: "get_history".to_string(),
+                description: "Fetches the historical weather for a specific location on a
+                past date (dt, formatted yyyy-MM-dd). Pass end_dt as well to get every day
+                from dt up to and including end_dt, at most 30 days.
+                The location and disambiguate parameters work as in get_weather.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<HistoryArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<Forecast>(),
+                }),
+            },
+            Tool {
+                name: "get_marine".to_string(),
+                description: "Fetches the marine and tide forecast for a coastal or sea
+                location for up to 7 days (days, default 1). Returns the swell height,
+                swell period, swell direction and water temperature per hour, and the high
+                and low tides per day.
+                The location and disambiguate parameters work as in get_weather.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<MarineArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<Marine>(),
+                }),
+            },
+            Tool {
+                name: "get_astronomy".to_string(),
+                description: "Fetches the sun and moon data for a specific location on a
+                date (dt, formatted yyyy-MM-dd, default today): sunrise, sunset, moonrise,
+                moonset, moon phase, moon illumination and whether the sun and moon are up.
+                The location and disambiguate parameters work as in get_weather.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<AstronomyArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<Astronomy>(),
+                }),
+            },
+            Tool {
+                name: "search_locations".to_string(),
+                description: "Searches the cities and towns matching a (partial) name passed
+                as query, e.g. query=Springfield. Returns the name, region, country,
+                latitude, longitude and id of every match. Pass the returned location
+                (id:<id>) as the location of the other tools to pick exactly that place.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<SearchArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<Search>(),
+                }),
+            },
+            Tool {
+                name: "lookup_ip".to_string(),
+                description: "Looks up where an IP address (IPv4 or IPv6) is located: city,
+                region, country, coordinates and time zone. Leave out ip to look up the
+                caller's own address, which is what location=auto:ip resolves to.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<IpArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<IpLookup>(),
+                }),
+            },
+            Tool {
+                name: "get_timezone".to_string(),
+                description: "Fetches the time zone and current local time of a specific
+                location, along with the place it resolved to.
+                The location and disambiguate parameters work as in get_weather.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<LocationArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<Location>(),
+                }),
+            },
+            Tool {
+                name: "get_alerts".to_string(),
+                description: "Fetches the active government weather alerts for a specific
+                location. Every alert has a headline, event, severity, urgency, certainty,
+                affected areas, effective and expiry times, description and instructions.
+                An empty list means there are no active alerts.
+                The location and disambiguate parameters work as in get_weather.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<LocationArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<AlertsReport>(),
+                }),
+            },
+            Tool {
+                name: "get_air_quality".to_string(),
+                description: "Fetches the air quality of a specific location: CO, NO2, O3, SO2,
+                PM2.5 and PM10 concentrations (μg/m3), the US EPA index (1 good to 6
+                hazardous) and the UK DEFRA index (1 low to 10 very high), plus pollen
+                counts where available. Pass days (1-14) to add the daily air quality
+                forecast.
+                The location and disambiguate parameters work as in get_weather.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<AirQualityArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<AirQualityReport>(),
+                }),
+            },
+            Tool {
+                name: "get_sports_events".to_string(),
+                description: "Fetches the upcoming football, cricket and golf events near a
+                specific location, with the match, tournament, stadium and local start time
+                of each. Combine with get_forecast to check the weather at an event.
+                The location and disambiguate parameters work as in get_weather.".to_string(),
+                input_schema: Value {
+                    json: input_schema::<LocationArguments>(),
+                },
+                output_schema: Some(Value {
+                    json: output_schema::<SportsEvents>(),
+                }),
+            },
+            Tool {
+                name: "get_future_weather".to_string(),
+                description: "Fetches the expected weather for a specific location on a date
+                (dt, formatted yyyy-MM-dd) between 14 and 300 days from today, e.g. to plan
+                an event months ahead. Use get_forecast for the next 14 days.
+                The location and disambiguate parameters work as in get_weather.".to_string(),
                 input_schema: Value {
-                    json: r#"{
-                        "type": "object",
-                        "properties": {
-                            "location": {
-                                "type": "string"
-                            }
-                        },
-                        "required": [
-                            "location"
-                        ]
-                    }"#.to_string(),
+                    json: input_schema::<FutureArguments>(),
                 },
                 output_schema: Some(Value {
-                    json: r#"{
-                        "title": "Forecast",
-                        "type": "object",
-                        "properties": {
-                            "forecastday": {
-                                "title": "Forecastday",
-                                "anyOf": [
-                                    {
-                                        "type": "array",
-                                        "items": {
-                                            "$ref": "\#/$defs/ForecastForecastdayInner"
-                                        }
-                                    },
-                                    { "type": "null" }
-                                ]
-                            }
-                        },
-                        "required": ["forecastday"],
-                        "$defs": {
-                            "ForecastForecastdayInner": {
-                                "type": "object",
-                                "title": "ForecastForecastdayInner",
-                                "properties": {
-                                    "date": { "type": ["string", "null"] },
-                                    "date_epoch": { "type": ["integer", "null"] },
-                                    "day": {
-                                        "anyOf": [
-                                            { "$ref": "\#/$defs/ForecastForecastdayInnerDay" },
-                                            { "type": "null" }
-                                        ]
-                                    },
-                                    "astro": {
-                                        "anyOf": [
-                                            { "$ref": "\#/$defs/ForecastForecastdayInnerAstro" },
-                                            { "type": "null" }
-                                        ]
-                                    },
-                                    "hour": {
-                                        "anyOf": [
-                                            {
-                                                "type": "array",
-                                                "items": { "$ref": "\#/$defs/ForecastForecastdayInnerHourInner" }
-                                            },
-                                            { "type": "null" }
-                                        ]
-                                    }
-                                },
-                                "required": ["date", "date_epoch", "day", "astro", "hour"]
-                            },
-                            "ForecastForecastdayInnerDay": {
-                                "type": "object",
-                                "title": "ForecastForecastdayInnerDay",
-                                "properties": {
-                                    "maxtemp_c": { "type": ["number", "null"] },
-                                    "mintemp_c": { "type": ["number", "null"] },
-                                    "uv": { "type": ["integer", "null"] }
-                                }
-                            },
-                            "ForecastForecastdayInnerAstro": {
-                                "type": "object",
-                                "properties": {
-                                    "sunrise": { "type": ["string", "null"] },
-                                    "sunset": { "type": ["string", "null"] }
-                                }
-                            },
-                            "ForecastForecastdayInnerHourInner": {
-                                "type": "object",
-                                "properties": {
-                                    "time": { "type": ["string", "null"] },
-                                    "temp_c": { "type": ["number", "null"] },
-                                    "uv": { "type": ["integer", "null"] }
-                                }
-                            }
-                        }
-                    }"#.to_string(),
+                    json: output_schema::<Forecast>(),
                 }),
             }]
         }
 
     fn call_tool(tool_name: String, arguments: Value) -> Result<CallToolResult, ToolError> {
-        logging::log(logging::Level::Info, "call_tool", format!("called: {} with args: {:?}",tool_name,arguments).as_str());
+        logging::log(logging::Level::Info, "call_tool", format!("called: {} with args: {:?}",tool_name,logged_arguments(&arguments)).as_str());
+        response_cache().forget_hits();
+        collect_revalidations();
         // Handle calling the tool, returning the appropriate result
-        if tool_name == "get_weather" {
+        match tool_name.as_str() {
+            "get_weather" => {
+                let args: LocationArguments = parse_arguments(&arguments)?;
+                if args.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+                if let Some(candidates) = ambiguous_location(&args, &tool_name)? {
+                    return Ok(candidates);
+                }
+
+                let weather: CurrentWeather = parse_response(&fetch("/v1/current.json", &[("q", args.location)])?)?;
+                json_result(&weather)
+            }
+            "get_forecast" => {
+                let args: ForecastArguments = parse_arguments(&arguments)?;
+                if args.place.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+                if let Some(candidates) = ambiguous_location(&args.place, &tool_name)? {
+                    return Ok(candidates);
+                }
+
+                let days = args.days.unwrap_or(1);
+                let mut query = vec![
+                    ("q", args.place.location),
+                    ("days", days.to_string()),
+                    ("aqi", yes_no(args.aqi.unwrap_or(false))),
+                    ("alerts", yes_no(args.alerts.unwrap_or(false))),
+                ];
+                if let Some(hour) = args.hour {
+                    query.push(("hour", hour.to_string()));
+                }
+
+                let response: ForecastResponse = parse_response(&fetch("/v1/forecast.json", &query)?)?;
+                json_result(&Forecast::from(response))
+            }
+            "get_history" => {
+                let args: HistoryArguments = parse_arguments(&arguments)?;
+                if args.place.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+                if let Some(candidates) = ambiguous_location(&args.place, &tool_name)? {
+                    return Ok(candidates);
+                }
 
-            let args: serde_json::Value = serde_json::from_str(&arguments.json).expect(format!("Could not read the json arguments: {}",&arguments.json).as_str());
+                let dt = args.dt;
+                let end_dt = args.end_dt.unwrap_or(dt);
+                if end_dt < dt {
+                    return Err(ToolError::InvalidParameters("end_dt must not be before dt".to_string()));
+                }
+                if dt.days_until(end_dt) >= MAX_HISTORY_DAYS {
+                    return Err(ToolError::InvalidParameters(format!("end_dt must be within {} days of dt", MAX_HISTORY_DAYS)));
+                }
 
-            let location = args
-                .get("location")
-                .and_then(|v| v.as_str())
-                .unwrap(); // Default location
-      
-            if location.is_empty() {
-                return Ok(CallToolResult{ content: vec![Content::Text(TextContent{text:"you need to provide a location".to_string(),annotations:None})], is_error: Some(true) });
+                json_result(&fetch_history(&args.place.location, dt, end_dt)?)
             }
+            "get_marine" => {
+                let args: MarineArguments = parse_arguments(&arguments)?;
+                if args.place.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+                if let Some(candidates) = ambiguous_location(&args.place, &tool_name)? {
+                    return Ok(candidates);
+                }
 
-            let weather_key = get(WEATHER_API_KEY);
-            let secret = reveal(&weather_key.expect("Could not read WEATHER_API_KEY value"));
-  
-            let url = format!(
-                "/v1/current.json?key={}&q={}",
-                secret.secret.clone(), 
-                encode(location));
-
-
-    
-            let req = OutgoingRequest::new(Fields::new());
-            req.set_scheme(Some(&Scheme::Https)).unwrap();
-            req.set_authority(Some("api.weatherapi.com")).unwrap();
-            req.set_path_with_query(Some(&url)).unwrap();
-   
-            // Perform the API call to the weather api, expecting a URL to come back as the response body
-            return match handle(req, None) {
-                Ok(resp) => {
-                    resp.subscribe().block();
-                    let response = resp
-                        .get()
-                        .expect("HTTP request response missing")
-                        .expect("HTTP request response requested more than once")
-                        .expect("HTTP request failed");
-
-                    if response.status() == 200 {
-                        let response_body = response
-                            .consume()
-                            .expect("failed to get incoming request body");
-                        let mut body = Vec::<u8>::new();
-                        let stream = response_body
-                            .stream()
-                            .expect("failed to get HTTP request response stream");
-             
-                        let chunk_size: u64= 1024;
-                        loop {
-                            let bytes_read = stream.blocking_read(chunk_size).unwrap();
-                            if bytes_read.len() < chunk_size as usize {
-                                body.extend_from_slice(&bytes_read);
-                                break;
-                            }
-                            body.extend_from_slice(&bytes_read);
-                        }
-                        let text = String::from_utf8(body).expect("Invalid UTF-8");
-                       
-                        Ok(CallToolResult {
-                            content: vec![Text(TextContent {
-                                text: text,
-                                annotations: None,
-                            })],
-                            is_error: Some(false),
-                        })
-                        
-                    } else {
-                        Err(ToolError::ExecutionError(format!("HTTP request failed with status code {}", response.status())))
-                    }   
+                let days = args.days.unwrap_or(1);
+                let query = [
+                    ("q", args.place.location),
+                    ("days", days.to_string()),
+                    ("tides", yes_no(true)),
+                ];
+                let response: MarineResponse = parse_response(&fetch("/v1/marine.json", &query)?)?;
+                json_result(&Marine::from(response))
+            }
+            "get_astronomy" => {
+                let args: AstronomyArguments = parse_arguments(&arguments)?;
+                if args.place.location.trim().is_empty() {
+                    return Err(missing_location());
                 }
-                Err(e) => {
-                    Err(ToolError::ExecutionError(format!("Got error when trying to fetch the weather: {}", e)))
+                if let Some(candidates) = ambiguous_location(&args.place, &tool_name)? {
+                    return Ok(candidates);
                 }
-                
-            };
 
-        } else {
-            Err(ToolError::NotFound(format!("Tool {} not found", tool_name)))
+                // weatherapi.com requires dt, and with it in the cache key a new day is never served the previous one
+                let dt = args.dt.unwrap_or_else(|| Date::from_unix_seconds(wall_clock::now().seconds));
+                let query = vec![("q", args.place.location), ("dt", dt.to_string())];
+                let response: AstronomyResponse = parse_response(&fetch("/v1/astronomy.json", &query)?)?;
+                json_result(&Astronomy::from(response))
+            }
+            "search_locations" => {
+                let args: SearchArguments = parse_arguments(&arguments)?;
+                if args.query.trim().is_empty() {
+                    return Err(ToolError::InvalidParameters("you need to provide a query".to_string()));
+                }
+
+                json_result(&Search { locations: search_locations(&args.query)? })
+            }
+            "lookup_ip" => {
+                let args: IpArguments = parse_arguments(&arguments)?;
+                let ip = match args.ip.as_deref().map(str::trim) {
+                    None | Some("") => "auto:ip",
+                    Some(ip) => ip,
+                };
+
+                let lookup: IpLookup = parse_response(&fetch("/v1/ip.json", &[("q", ip.to_string())])?)?;
+                json_result(&lookup)
+            }
+            "get_timezone" => {
+                let args: LocationArguments = parse_arguments(&arguments)?;
+                if args.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+                if let Some(candidates) = ambiguous_location(&args, &tool_name)? {
+                    return Ok(candidates);
+                }
+
+                let response: TimezoneResponse = parse_response(&fetch("/v1/timezone.json", &[("q", args.location)])?)?;
+                json_result(&response.location)
+            }
+            "get_alerts" => {
+                let args: LocationArguments = parse_arguments(&arguments)?;
+                if args.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+                if let Some(candidates) = ambiguous_location(&args, &tool_name)? {
+                    return Ok(candidates);
+                }
+
+                let response: AlertsResponse = parse_response(&fetch("/v1/alerts.json", &[("q", args.location)])?)?;
+                json_result(&AlertsReport::from(response))
+            }
+            "get_air_quality" => {
+                let args: AirQualityArguments = parse_arguments(&arguments)?;
+                if args.place.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+                if let Some(candidates) = ambiguous_location(&args.place, &tool_name)? {
+                    return Ok(candidates);
+                }
+
+                let days = args.days;
+                let mut query = vec![
+                    ("q", args.place.location),
+                    ("aqi", yes_no(true)),
+                    ("pollen", yes_no(true)),
+                ];
+                let endpoint = match days {
+                    Some(days) => {
+                        query.push(("days", days.to_string()));
+                        "/v1/forecast.json"
+                    }
+                    None => "/v1/current.json",
+                };
+                let response: AirQualityResponse = parse_response(&fetch(endpoint, &query)?)?;
+                json_result(&AirQualityReport::from(response))
+            }
+            "get_sports_events" => {
+                let args: LocationArguments = parse_arguments(&arguments)?;
+                if args.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+                if let Some(candidates) = ambiguous_location(&args, &tool_name)? {
+                    return Ok(candidates);
+                }
+
+                let events: SportsEvents = parse_response(&fetch("/v1/sports.json", &[("q", args.location)])?)?;
+                json_result(&events)
+            }
+            "get_future_weather" => {
+                let args: FutureArguments = parse_arguments(&arguments)?;
+                if args.place.location.trim().is_empty() {
+                    return Err(missing_location());
+                }
+
+                let dt = args.dt;
+                let today = Date::from_unix_seconds(wall_clock::now().seconds);
+                let (first, last) = (today.add_days(MIN_FUTURE_DAYS), today.add_days(MAX_FUTURE_DAYS));
+                if dt < first || dt > last {
+                    return Err(ToolError::InvalidParameters(format!(
+                        "dt must be between {} and {} days from today, so from {} up to and including {}; use get_forecast for earlier dates",
+                        MIN_FUTURE_DAYS, MAX_FUTURE_DAYS, first, last)));
+                }
+                if let Some(candidates) = ambiguous_location(&args.place, &tool_name)? {
+                    return Ok(candidates);
+                }
+
+                let response: ForecastResponse = parse_response(&fetch("/v1/future.json", &[("q", args.place.location), ("dt", dt.to_string())])?)?;
+                json_result(&Forecast::from(response))
+            }
+            _ => Err(ToolError::NotFound(format!("Tool {} not found", tool_name))),
         }
     }
 
@@ -269,6 +571,13 @@ impl Guest for WeatherAPIRouter{
                 mime_type: "application/json".to_string(),
                 annotations: None,
             },
+            McpResource {
+                uri: DIAGNOSTICS_URI.to_string(),
+                name: "Diagnostics".to_string(),
+                description: Some("The state of the circuit breaker that fails the tools fast while weatherapi.com is down, with its failure counts.".to_string()),
+                mime_type: "application/json".to_string(),
+                annotations: None,
+            },
         ]
         
     }
@@ -283,6 +592,15 @@ impl Guest for WeatherAPIRouter{
                     text: "{\"weather\": \"sunny\", \"temperature\":\"15 degrees\"}".to_string(),
                 })],
             })
+        } else if uri == DIAGNOSTICS_URI {
+            let status = circuit_breaker().status(monotonic_clock::now());
+            Ok(ReadResourceResult {
+                contents: vec![ResourceContents::Text(TextResourceContents {
+                    text: serde_json::to_string(&status).map_err(|e| ResourceError::ExecutionError(e.to_string()))?,
+                    uri,
+                    mime_type: Some("application/json".to_string()),
+                })],
+            })
         } else {
             Err(ResourceError::NotFound(format!("Resource at {} not found", uri)))
         }
@@ -329,3 +647,612 @@ impl Guest for WeatherAPIRouter{
         }
     }
 }
+
+/// Deserializes the json arguments passed to a tool after checking them against its input schema.
+fn parse_arguments<T: DeserializeOwned + JsonSchema>(arguments: &Value) -> Result<T, ToolError> {
+    let args: serde_json::Value = serde_json::from_str(&arguments.json)
+        .map_err(|e| ToolError::InvalidParameters(format!("Could not read the json arguments {}: {}", arguments.json, e)))?;
+    check_arguments::<T>(&args).map_err(ToolError::InvalidParameters)?;
+    serde_json::from_value(args)
+        .map_err(|e| ToolError::InvalidParameters(format!("Could not read the json arguments {}: {}", arguments.json, e)))
+}
+
+/// Converts a flag into the yes/no form weatherapi.com expects.
+fn yes_no(flag: bool) -> String {
+    if flag { "yes" } else { "no" }.to_string()
+}
+
+/// Returns the arguments as they are logged, with the location in its canonical form.
+fn logged_arguments(arguments: &Value) -> Value {
+    let decimals = optional_number(WEATHER_API_COORDINATE_DECIMALS, COORDINATE_DECIMALS).unwrap_or(COORDINATE_DECIMALS);
+    let mut json = match serde_json::from_str::<serde_json::Value>(&arguments.json) {
+        Ok(json) => json,
+        Err(_) => return arguments.clone(),
+    };
+    match json.get_mut("location") {
+        Some(serde_json::Value::String(location)) => *location = Place::parse(location).canonical(decimals),
+        _ => return arguments.clone(),
+    }
+    Value { json: json.to_string() }
+}
+
+fn missing_location() -> ToolError {
+    ToolError::InvalidParameters("you need to provide a location".to_string())
+}
+
+/// Locks state that lives as long as the component instance, such as the cache and the circuit breaker.
+fn lock_instance_state<T>(state: &'static Mutex<T>) -> MutexGuard<'static, T> {
+    // A guest has a single thread, so the lock is never contended and a poisoned one is still consistent
+    state.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn text_result(text: String) -> CallToolResult {
+    CallToolResult {
+        content: vec![Text(TextContent { text, annotations: None })],
+        is_error: Some(false),
+    }
+}
+
+/// Serializes a tool result as the json text of a successful result.
+///
+/// A result built from cached responses carries the time of the oldest one as its timestamp,
+/// followed by a note for the assistant saying how old the data is, and why when it is stale.
+fn json_result<T: Serialize>(result: &T) -> Result<CallToolResult, ToolError> {
+    let text = serde_json::to_string(result)
+        .map_err(|e| ToolError::SchemaError(format!("Could not serialize the tool result: {}", e)))?;
+    let hits = response_cache().hits().to_vec();
+    let Some(fetched_at) = hits.iter().map(|hit| hit.fetched_at_seconds).min() else {
+        return Ok(text_result(text));
+    };
+    let timestamp = format_timestamp(fetched_at);
+    let age = wall_clock::now().seconds.saturating_sub(fetched_at);
+    let failed = hits.iter().find_map(|hit| match &hit.stale {
+        Some(Stale::Failed(because)) => Some(because),
+        _ => None,
+    });
+    let note = match failed {
+        Some(because) => format!(
+            "Stale: served from the cache because weatherapi.com failed ({}); fetched {} seconds ago, at {}, and no longer fresh.",
+            because, age, timestamp),
+        None if hits.iter().any(|hit| hit.stale == Some(Stale::Revalidating)) => format!(
+            "Stale: served from the cache while weatherapi.com is asked for a fresh response, which later calls get; fetched {} seconds ago, at {}, and no longer fresh.",
+            age, timestamp),
+        None => format!("Served from the cache: fetched from weatherapi.com {} seconds ago, at {}.", age, timestamp),
+    };
+    Ok(CallToolResult {
+        content: vec![
+            Text(TextContent {
+                text,
+                annotations: Some(Annotations { audience: None, priority: None, timestamp: Some(timestamp.clone()) }),
+            }),
+            Text(TextContent {
+                text: note,
+                annotations: Some(Annotations { audience: Some(vec![Role::Assistant]), priority: None, timestamp: Some(timestamp) }),
+            }),
+        ],
+        is_error: Some(false),
+    })
+}
+
+/// Deserializes a weatherapi.com response body into its model.
+fn parse_response<T: DeserializeOwned>(text: &str) -> Result<T, ToolError> {
+    serde_json::from_str(text)
+        .map_err(|e| ToolError::SchemaError(format!("The weather api response does not have the expected shape: {}", e)))
+}
+
+/// Fetches the history for `dt..=end_dt` as a single Forecast.
+///
+/// The range is requested in one call first. Plans that only serve a single day per request
+/// either reject `end_dt` or return fewer days, so any missing day is then fetched on its own.
+fn fetch_history(location: &str, dt: Date, end_dt: Date) -> Result<Forecast, ToolError> {
+    let mut query = vec![("q", location.to_string()), ("dt", dt.to_string())];
+    if end_dt != dt {
+        query.push(("end_dt", end_dt.to_string()));
+    }
+    let (mut found_location, mut days): (Option<Location>, Vec<ForecastDay>) =
+        match fetch("/v1/history.json", &query).and_then(|text| parse_response::<ForecastResponse>(&text)) {
+            Ok(response) => (Some(response.location), response.forecast.forecastday),
+            Err(e) if end_dt == dt => return Err(e),
+            Err(_) => (None, Vec::new()),
+        };
+
+    let mut date = dt;
+    while date <= end_dt {
+        let date_text = date.to_string();
+        if !days.iter().any(|day| day.date == date_text) {
+            let response: ForecastResponse = parse_response(&fetch("/v1/history.json", &[("q", location.to_string()), ("dt", date_text)])?)?;
+            found_location.get_or_insert(response.location);
+            days.extend(response.forecast.forecastday);
+        }
+        date = date.next();
+    }
+
+    days.retain(|day| Date::parse(&day.date).is_some_and(|d| dt <= d && d <= end_dt));
+    days.sort_by(|a, b| a.date.cmp(&b.date));
+    Ok(Forecast { location: found_location.unwrap_or_default(), forecastday: days, alerts: None })
+}
+
+/// Checks whether a place name matches several places when the `disambiguate` argument is set.
+///
+/// weatherapi.com silently picks one place for an ambiguous name, so instead the strong
+/// candidates are listed with their `id:` locations for the agent to ask which one is meant.
+fn ambiguous_location(args: &LocationArguments, tool_name: &str) -> Result<Option<CallToolResult>, ToolError> {
+    let location = args.location.as_str();
+    // Coordinates, postal codes, ids, airport codes and IP addresses already name a single place
+    if !args.disambiguate.unwrap_or(false) || location.contains(':') || location.chars().any(|c| c.is_ascii_digit()) {
+        return Ok(None);
+    }
+
+    let parts: Vec<String> = location.split(',')
+        .map(|part| part.trim().to_lowercase())
+        .filter(|part| !part.is_empty())
+        .collect();
+    let Some((name, qualifiers)) = parts.split_first() else {
+        return Ok(None);
+    };
+    let found = search_locations(location)?;
+    // The result is built from the responses fetched next, not from the search
+    response_cache().forget_hits();
+    let candidates: Vec<SearchLocation> = found.into_iter().filter(|candidate| {
+        let field = |value: &Option<String>| value.as_deref().unwrap_or_default().to_lowercase();
+        field(&candidate.name) == *name
+            && qualifiers.iter().all(|q| field(&candidate.region).starts_with(q.as_str()) || field(&candidate.country).starts_with(q.as_str()))
+    }).collect();
+    if candidates.len() < 2 {
+        return Ok(None);
+    }
+
+    let mut text = format!(
+        "{} matches {} places. Ask which one is meant and call {} again with its location:",
+        location, candidates.len(), tool_name);
+    for candidate in &candidates {
+        text.push_str(&format!(
+            "\n* location={} {}, {}, {} ({}, {})",
+            candidate.location,
+            candidate.name.as_deref().unwrap_or_default(),
+            candidate.region.as_deref().unwrap_or_default(),
+            candidate.country.as_deref().unwrap_or_default(),
+            candidate.lat.unwrap_or_default(), candidate.lon.unwrap_or_default()));
+    }
+    Ok(Some(text_result(text)))
+}
+
+/// Searches the locations matching `query`, each with the `id:` location that selects it exactly.
+fn search_locations(query: &str) -> Result<Vec<SearchLocation>, ToolError> {
+    let mut locations: Vec<SearchLocation> = parse_response(&fetch("/v1/search.json", &[("q", query.to_string())])?)?;
+    for found in &mut locations {
+        found.location = format!("id:{}", found.id);
+    }
+    Ok(locations)
+}
+
+/// Calls a weatherapi.com endpoint with the given query parameters and returns the response body.
+///
+/// The location in `q` is cached in its canonical form, so the places it shares a grid cell or a spelling
+/// with share the response, but it is requested as written, at the precision it was given in.
+fn fetch(endpoint: &str, query: &[(&str, String)]) -> Result<String, ToolError> {
+    let decimals = optional_number(WEATHER_API_COORDINATE_DECIMALS, COORDINATE_DECIMALS)?;
+    let canonical_query: Vec<(&str, String)> = query.iter()
+        .map(|(name, value)| match *name {
+            "q" => (*name, Place::parse(value).canonical(decimals)),
+            _ => (*name, value.clone()),
+        })
+        .collect();
+    let key = cache_key(endpoint, &canonical_query);
+    if let Some(body) = response_cache().get(&key, monotonic_clock::now(), wall_clock::now().seconds) {
+        return Ok(body);
+    }
+
+    let secret = reveal(&get(WEATHER_API_KEY).map_err(|e| match e {
+        SecretsError::NotFound => ToolError::ExecutionError(format!(
+            "The {} secret is not set; set it to your weatherapi.com api key", WEATHER_API_KEY)),
+        SecretsError::Upstream(message) | SecretsError::Io(message) => ToolError::ExecutionError(format!(
+            "Could not read the {} secret, which should hold your weatherapi.com api key: {}", WEATHER_API_KEY, message)),
+    })?);
+    if secret.secret.trim().is_empty() {
+        return Err(ToolError::ExecutionError(format!("The {} secret is empty; set it to your weatherapi.com api key", WEATHER_API_KEY)));
+    }
+
+    let mut url = format!("{}?key={}", endpoint, encode(secret.secret.trim()));
+    for (name, value) in query {
+        url.push_str(&format!("&{}={}", name, encode(value)));
+    }
+
+    let limits = Limits::read()?;
+    let policy = stale_policy()?;
+    let max_stale_ms = policy.max_stale_ms(endpoint);
+    circuit_breaker().configure(
+        optional_number(WEATHER_API_FAILURE_THRESHOLD, FAILURE_THRESHOLD)?,
+        optional_number(WEATHER_API_COOL_DOWN_MS, COOL_DOWN_MS)?);
+    let permit = match circuit_breaker().admit(monotonic_clock::now()) {
+        Ok(permit) => permit,
+        Err(retry_after_ms) => return serve_stale(&key, max_stale_ms, ToolError::ExecutionError(format!(
+            "weather provider unavailable, retry after {} seconds", retry_after_ms.div_ceil(1000)))),
+    };
+    // An endpoint that revalidates answers with the stale response at once, and a later call caches the
+    // fresh one. Otherwise the answer waits for the api, which with a stale response at hand gets a single
+    // attempt, so a failure serves the stale response without waiting out retries
+    let now_ns = monotonic_clock::now();
+    let has_stale = response_cache().has_stale(&key, now_ns, max_stale_ms);
+    if has_stale && permit == Permit::Call && policy.revalidates(endpoint) {
+        let freshness = freshness(endpoint, &canonical_query, wall_clock::now().seconds);
+        if let Err(Failure { error, .. }) = revalidate(&key, freshness, max_stale_ms, endpoint, &url, &limits) {
+            circuit_breaker().record_failure(monotonic_clock::now(), format!("{}: {:?}", endpoint, error));
+            return serve_stale(&key, max_stale_ms, error);
+        }
+        if let Some(body) = response_cache().get_stale(&key, now_ns, max_stale_ms, Stale::Revalidating) {
+            return Ok(body);
+        }
+    }
+    let max_attempts = if permit == Permit::Probe || has_stale { 1 } else { MAX_ATTEMPTS };
+    let mut quota_exceeded = false;
+    let mut attempt = 1;
+    let (result, upstream_failed) = loop {
+        let (error, retry_after_ms) = match send(endpoint, &url, &limits) {
+            Ok(response) if response.status == 200 => {
+                break (String::from_utf8(response.body)
+                    .map_err(|e| ToolError::SchemaError(format!("The weather api response is not valid UTF-8: {}", e))), false);
+            }
+            // Rate limiting and server errors pass, other 4xx errors such as an unknown location or key do not
+            Ok(response) if response.status == 429 || response.status >= 500 => {
+                if let Some(retry_after_ms) = response.retry_after_ms.filter(|ms| *ms > MAX_RETRY_AFTER_MS) {
+                    break (Err(ToolError::ExecutionError(format!(
+                        "The weather api failed with status code {} and asked to retry after {} seconds",
+                        response.status, retry_after_ms.div_ceil(1000)))), true);
+                }
+                (api_error(response.status, &response.body, query), response.retry_after_ms)
+            }
+            Ok(response) => {
+                quota_exceeded = serde_json::from_slice::<ErrorResponse>(&response.body)
+                    .is_ok_and(|ErrorResponse { error }| error.code == Some(QUOTA_EXCEEDED));
+                break (Err(api_error(response.status, &response.body, query)), false);
+            }
+            Err(Failure { error, transient: true }) => (error, None),
+            Err(Failure { error, .. }) => break (Err(error), true),
+        };
+        if attempt == max_attempts {
+            break (Err(error), true);
+        }
+        let delay_ms = backoff_ms(attempt).max(retry_after_ms.unwrap_or_default());
+        logging::log(logging::Level::Warn, "fetch", &format!(
+            "Retrying {} in {} ms after attempt {} of {} failed: {:?}", key, delay_ms, attempt, max_attempts, error));
+        monotonic_clock::subscribe_duration(delay_ms * 1_000_000).block();
+        attempt += 1;
+    };
+
+    match &result {
+        Ok(body) => {
+            circuit_breaker().record_success();
+            let now_seconds = wall_clock::now().seconds;
+            let freshness = freshness(endpoint, &canonical_query, now_seconds);
+            response_cache().insert(freshness, key, body, monotonic_clock::now(), now_seconds, max_stale_ms);
+            result
+        }
+        Err(error) if upstream_failed => {
+            circuit_breaker().record_failure(monotonic_clock::now(), format!("{}: {:?}", endpoint, error));
+            serve_stale(&key, max_stale_ms, error.clone())
+        }
+        Err(_) => {
+            circuit_breaker().record_success();
+            match result {
+                Err(error) if quota_exceeded => serve_stale(&key, max_stale_ms, error),
+                result => result,
+            }
+        }
+    }
+}
+
+/// Serves the stale cached response for `key` in place of the `error` fetching it failed with,
+/// or returns the error when no response expired less than `max_stale_ms` ago.
+fn serve_stale(key: &str, max_stale_ms: u64, error: ToolError) -> Result<String, ToolError> {
+    let because = match &error {
+        ToolError::InvalidParameters(message) | ToolError::ExecutionError(message)
+        | ToolError::SchemaError(message) | ToolError::NotFound(message) => message.clone(),
+    };
+    match response_cache().get_stale(key, monotonic_clock::now(), max_stale_ms, Stale::Failed(because)) {
+        Some(body) => {
+            logging::log(logging::Level::Warn, "fetch", &format!("Serving a stale response for {} after: {:?}", key, error));
+            Ok(body)
+        }
+        None => Err(error),
+    }
+}
+
+/// A request for a fresh response, started while the stale one was served.
+struct Revalidation {
+    key: String,
+    endpoint: String,
+    freshness: Freshness,
+    max_stale_ms: u64,
+    limits: Limits,
+    started_at_ns: u64,
+    started_at_seconds: u64,
+    response: FutureIncomingResponse,
+}
+
+/// The revalidations whose responses were not collected yet.
+static REVALIDATIONS: Mutex<Vec<Revalidation>> = Mutex::new(Vec::new());
+
+/// Starts fetching a fresh response for `key` in the background, unless that is already under way.
+fn revalidate(key: &str, freshness: Freshness, max_stale_ms: u64, endpoint: &str, url: &str, limits: &Limits) -> Result<(), Failure> {
+    let mut revalidations = lock_instance_state(&REVALIDATIONS);
+    if revalidations.iter().any(|revalidation| revalidation.key == key) {
+        return Ok(());
+    }
+    let response = start(endpoint, url, limits)?;
+    revalidations.push(Revalidation {
+        key: key.to_string(),
+        endpoint: endpoint.to_string(),
+        freshness,
+        max_stale_ms,
+        limits: limits.clone(),
+        started_at_ns: monotonic_clock::now(),
+        started_at_seconds: wall_clock::now().seconds,
+        response,
+    });
+    Ok(())
+}
+
+/// Caches the fresh responses of the revalidations that completed, leaving the others pending.
+///
+/// A revalidation that failed keeps the stale response and counts towards the circuit breaker.
+fn collect_revalidations() {
+    let pending = std::mem::take(&mut *lock_instance_state(&REVALIDATIONS));
+    for revalidation in pending {
+        // The response only becomes available once its pollable reports it ready, which never blocks
+        if !revalidation.response.subscribe().ready() {
+            lock_instance_state(&REVALIDATIONS).push(revalidation);
+            continue;
+        }
+        let response = revalidation.response.get();
+        let Revalidation { key, endpoint, freshness, max_stale_ms, limits, started_at_ns, started_at_seconds, .. } = revalidation;
+        let error = match receive(response, &limits) {
+            Ok(response) if response.status == 200 => match String::from_utf8(response.body) {
+                Ok(body) => {
+                    circuit_breaker().record_success();
+                    // The response is at least as fresh as the request, which is when it counts as fetched
+                    response_cache().insert(freshness, key, &body, started_at_ns, started_at_seconds, max_stale_ms);
+                    continue;
+                }
+                Err(e) => ToolError::SchemaError(format!("The weather api response is not valid UTF-8: {}", e)),
+            },
+            Ok(response) if response.status == 429 || response.status >= 500 => {
+                circuit_breaker().record_failure(monotonic_clock::now(), format!("{}: status code {}", endpoint, response.status));
+                ToolError::ExecutionError(format!("The weather api failed with status code {}", response.status))
+            }
+            Ok(response) => ToolError::ExecutionError(format!("The weather api rejected the request with status code {}", response.status)),
+            Err(Failure { error, .. }) => {
+                circuit_breaker().record_failure(monotonic_clock::now(), format!("{}: {:?}", endpoint, error));
+                error
+            }
+        };
+        logging::log(logging::Level::Warn, "fetch", &format!("Could not revalidate {}: {:?}", key, error));
+    }
+}
+
+/// The response to a single request to the weather api.
+struct ApiResponse {
+    status: u16,
+    retry_after_ms: Option<u64>,
+    body: Vec<u8>,
+}
+
+/// A request that failed before the weather api responded; transient when the connection failed.
+struct Failure {
+    error: ToolError,
+    transient: bool,
+}
+
+/// Sends a single request to the weather api and reads its response.
+fn send(endpoint: &str, url: &str, limits: &Limits) -> Result<ApiResponse, Failure> {
+    let response = start(endpoint, url, limits)?;
+    response.subscribe().block();
+    receive(response.get(), limits)
+}
+
+/// Starts a single request to the weather api, whose response is read with `receive` once it is ready.
+fn start(endpoint: &str, url: &str, limits: &Limits) -> Result<FutureIncomingResponse, Failure> {
+    let req = OutgoingRequest::new(Fields::new());
+    req.set_scheme(Some(&Scheme::Https))
+        .and_then(|_| req.set_authority(Some("api.weatherapi.com")))
+        .and_then(|_| req.set_path_with_query(Some(url)))
+        .map_err(|_| fatal(ToolError::ExecutionError(format!("Could not build the request to {}", endpoint))))?;
+
+    // Perform the API call to the weather api, giving up on a stalled upstream
+    let options = RequestOptions::new();
+    for (set, name) in [
+        (options.set_connect_timeout(Some(limits.connect_timeout_ms.saturating_mul(1_000_000))), WEATHER_API_CONNECT_TIMEOUT_MS),
+        (options.set_first_byte_timeout(Some(limits.first_byte_timeout_ms.saturating_mul(1_000_000))), WEATHER_API_FIRST_BYTE_TIMEOUT_MS),
+        (options.set_between_bytes_timeout(Some(limits.between_bytes_timeout_ms.saturating_mul(1_000_000))), WEATHER_API_BETWEEN_BYTES_TIMEOUT_MS),
+    ] {
+        if set.is_err() {
+            logging::log(logging::Level::Warn, "fetch", &format!("The host does not support the timeout set by {}", name));
+        }
+    }
+    handle(req, Some(options)).map_err(|e| failure(e, limits))
+}
+
+/// Reads the response of a request once `FutureIncomingResponse::get` returned it.
+fn receive(response: Option<Result<Result<IncomingResponse, ErrorCode>, ()>>, limits: &Limits) -> Result<ApiResponse, Failure> {
+    let response = match response {
+        Some(Ok(Ok(response))) => response,
+        Some(Ok(Err(e))) => return Err(failure(e, limits)),
+        Some(Err(())) | None => return Err(fatal(ToolError::ExecutionError("The weather api response was not available".to_string()))),
+    };
+    let status = response.status();
+    let retry_after_ms = retry_after(&response);
+    let body = read_body(response, limits).map_err(fatal)?;
+    Ok(ApiResponse { status, retry_after_ms, body })
+}
+
+/// Describes a request that failed with `error` before the weather api responded.
+fn failure(error: ErrorCode, limits: &Limits) -> Failure {
+    Failure {
+        transient: matches!(error,
+            ErrorCode::DnsTimeout | ErrorCode::DnsError(_) | ErrorCode::DestinationUnavailable
+            | ErrorCode::ConnectionRefused | ErrorCode::ConnectionTerminated
+            | ErrorCode::ConnectionTimeout | ErrorCode::ConnectionLimitReached),
+        error: match error {
+            ErrorCode::ConnectionTimeout => timeout_error(
+                "connecting", limits.connect_timeout_ms, WEATHER_API_CONNECT_TIMEOUT_MS),
+            ErrorCode::ConnectionReadTimeout => timeout_error(
+                "waiting for the response", limits.first_byte_timeout_ms, WEATHER_API_FIRST_BYTE_TIMEOUT_MS),
+            e => ToolError::ExecutionError(format!("Got error when trying to fetch the weather: {}", e)),
+        },
+    }
+}
+
+/// Describes a request that failed in a way retrying does not help.
+fn fatal(error: ToolError) -> Failure {
+    Failure { error, transient: false }
+}
+
+/// Returns the delay before retrying after the given attempt: exponential, with half of it random.
+///
+/// The randomness keeps the clients that failed together from retrying in lockstep.
+fn backoff_ms(attempt: u32) -> u64 {
+    let ceiling = BASE_BACKOFF_MS.saturating_mul(1 << (attempt - 1).min(16)).min(MAX_BACKOFF_MS);
+    ceiling / 2 + random::get_random_u64() % (ceiling / 2 + 1)
+}
+
+/// Reads the `Retry-After` header, in seconds or as an HTTP date, as milliseconds from now.
+fn retry_after(response: &IncomingResponse) -> Option<u64> {
+    let values = response.headers().get("retry-after");
+    let text = String::from_utf8(values.first()?.clone()).ok()?;
+    match text.trim().parse::<u64>() {
+        Ok(seconds) => Some(seconds.saturating_mul(1000)),
+        Err(_) => {
+            let seconds = parse_http_date(text.trim())?.saturating_sub(wall_clock::now().seconds as i64);
+            Some((seconds.max(0) as u64).saturating_mul(1000))
+        }
+    }
+}
+
+/// Reads the body of a weather api response until the stream is closed, then waits for its trailers.
+///
+/// Reads may return fewer bytes than asked for at any point of the stream, so only `Closed`
+/// ends the body. A body over the size limit fails before it is fully buffered.
+fn read_body(response: IncomingResponse, limits: &Limits) -> Result<Vec<u8>, ToolError> {
+    let between_bytes_timeout = || timeout_error(
+        "waiting for the rest of the response", limits.between_bytes_timeout_ms, WEATHER_API_BETWEEN_BYTES_TIMEOUT_MS);
+    let response_body = response.consume()
+        .map_err(|_| ToolError::ExecutionError("Could not read the weather api response body".to_string()))?;
+    let mut body = Vec::<u8>::new();
+    {
+        // The stream is a child of the body and has to be dropped before the body is finished
+        let stream = response_body.stream()
+            .map_err(|_| ToolError::ExecutionError("Could not read the weather api response body".to_string()))?;
+        loop {
+            match stream.blocking_read(64 * 1024) {
+                Ok(bytes_read) => {
+                    if body.len() as u64 + bytes_read.len() as u64 > limits.max_body_bytes {
+                        return Err(ToolError::ExecutionError(format!(
+                            "The weather api response is larger than {} bytes; raise {} to accept it",
+                            limits.max_body_bytes, WEATHER_API_MAX_BODY_BYTES)));
+                    }
+                    body.extend_from_slice(&bytes_read);
+                }
+                Err(StreamError::Closed) => break,
+                Err(StreamError::LastOperationFailed(e)) => return Err(match http_error_code(&e) {
+                    Some(ErrorCode::ConnectionReadTimeout) => between_bytes_timeout(),
+                    _ => ToolError::ExecutionError(format!("Got error when trying to read the weather: {}", e.to_debug_string())),
+                }),
+            }
+        }
+    }
+
+    // A body that failed after its last byte reports the error with the trailers
+    let trailers = IncomingBody::finish(response_body);
+    trailers.subscribe().block();
+    match trailers.get() {
+        Some(Ok(Ok(_))) => Ok(body),
+        Some(Ok(Err(ErrorCode::ConnectionReadTimeout))) => Err(between_bytes_timeout()),
+        Some(Ok(Err(e))) => Err(ToolError::ExecutionError(format!("Got error when trying to read the weather: {}", e))),
+        Some(Err(())) | None => Err(ToolError::ExecutionError("The weather api response trailers were not available".to_string())),
+    }
+}
+
+/// The size limit and timeouts of the weather api requests.
+#[derive(Clone)]
+struct Limits {
+    max_body_bytes: u64,
+    connect_timeout_ms: u64,
+    first_byte_timeout_ms: u64,
+    between_bytes_timeout_ms: u64,
+}
+
+impl Limits {
+    /// Reads the limits from their optional secrets, falling back to the defaults.
+    fn read() -> Result<Limits, ToolError> {
+        Ok(Limits {
+            max_body_bytes: optional_number(WEATHER_API_MAX_BODY_BYTES, MAX_BODY_BYTES)?,
+            connect_timeout_ms: optional_number(WEATHER_API_CONNECT_TIMEOUT_MS, CONNECT_TIMEOUT_MS)?,
+            first_byte_timeout_ms: optional_number(WEATHER_API_FIRST_BYTE_TIMEOUT_MS, FIRST_BYTE_TIMEOUT_MS)?,
+            between_bytes_timeout_ms: optional_number(WEATHER_API_BETWEEN_BYTES_TIMEOUT_MS, BETWEEN_BYTES_TIMEOUT_MS)?,
+        })
+    }
+}
+
+/// Reads the stale policy from its optional secrets, falling back to the defaults of every endpoint.
+fn stale_policy() -> Result<StalePolicy, ToolError> {
+    let text = match get(WEATHER_API_MAX_STALE_SECONDS) {
+        Ok(secret) => reveal(&secret).secret,
+        Err(_) => String::new(),
+    };
+    let policy = StalePolicy::parse(&text)
+        .map_err(|e| ToolError::ExecutionError(format!("The {} secret {}", WEATHER_API_MAX_STALE_SECONDS, e)))?;
+    let text = match get(WEATHER_API_STALE_WHILE_REVALIDATE) {
+        Ok(secret) => reveal(&secret).secret,
+        Err(_) => String::new(),
+    };
+    policy.revalidating(&text)
+        .map_err(|e| ToolError::ExecutionError(format!("The {} secret {}", WEATHER_API_STALE_WHILE_REVALIDATE, e)))
+}
+
+/// Reads an optional secret holding a number, or `default` when it is not set.
+fn optional_number(name: &str, default: u64) -> Result<u64, ToolError> {
+    let secret = match get(name) {
+        Ok(secret) => reveal(&secret).secret,
+        Err(_) => return Ok(default),
+    };
+    match secret.trim() {
+        "" => Ok(default),
+        text => text.parse().map_err(|_| ToolError::ExecutionError(format!(
+            "The {} secret must be a whole number, not {}", name, text))),
+    }
+}
+
+/// The error of a request that took longer than one of its timeouts.
+fn timeout_error(step: &str, timeout_ms: u64, secret: &str) -> ToolError {
+    ToolError::ExecutionError(format!(
+        "The weather api timed out after {} ms {}; it may be overloaded, so retry later or raise the {} secret",
+        timeout_ms, step, secret))
+}
+
+/// Turns a failed weather api response into a tool error the agent can act on.
+///
+/// weatherapi.com explains failures with an `{"error": {"code": .., "message": ..}}` body; the
+/// codes that tell whether to rephrase the location, fix the key or give up get their own message.
+fn api_error(status: u16, body: &[u8], query: &[(&str, String)]) -> ToolError {
+    let Ok(ErrorResponse { error }) = serde_json::from_slice::<ErrorResponse>(body) else {
+        return ToolError::ExecutionError(format!("HTTP request failed with status code {}", status));
+    };
+    let message = error.message.unwrap_or_default();
+    match error.code {
+        Some(1006) => {
+            let location = query.iter().find(|(name, _)| *name == "q").map(|(_, value)| value.as_str()).unwrap_or_default();
+            ToolError::InvalidParameters(format!(
+                "No place matching location {} was found. Call search_locations with a place name to find it and pass the id:<id> location it returns.",
+                location))
+        }
+        Some(2006) => ToolError::ExecutionError(format!(
+            "The {} secret is not a valid weatherapi.com api key ({}); fix the secret, retrying will not help.", WEATHER_API_KEY, message)),
+        Some(2008) => ToolError::ExecutionError(format!(
+            "The weatherapi.com api key in the {} secret has been disabled ({}); enable it or set another key, retrying will not help.", WEATHER_API_KEY, message)),
+        Some(2007) => ToolError::ExecutionError(format!(
+            "The weatherapi.com api key has exceeded its monthly call quota ({}); retrying will not help until the quota resets or the plan is upgraded.", message)),
+        Some(code) => ToolError::ExecutionError(format!(
+            "The weather api failed with status code {} and error code {}: {}", status, code, message)),
+        None => ToolError::ExecutionError(format!("The weather api failed with status code {}: {}", status, message)),
+    }
+}
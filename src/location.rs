@@ -0,0 +1,135 @@
+//! The locations weatherapi.com accepts, written in a canonical form.
+//!
+//! The same place can be written many ways: "Paris" and " paris ", "sw1a1aa" and "SW1A 1AA", or
+//! "48.8567,2.3508" and "48.86,2.35" once coordinates are rounded to a grid. The cache keys use
+//! the canonical form, so these share a cache entry, and so do the logs, while the requests keep the
+//! location as written.
+
+use std::net::IpAddr;
+
+/// Coordinates are rounded to this many decimals unless configured otherwise, a grid of about a kilometre.
+pub const COORDINATE_DECIMALS: u64 = 2;
+/// Coordinates are rounded to at most this many decimals, about a centimetre, as more would only tell apart the same spot.
+pub const MAX_COORDINATE_DECIMALS: u64 = 7;
+
+/// A location in one of the formats listed in the instructions.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Place {
+    /// Latitude and longitude in decimal degrees.
+    Coordinates { lat: f64, lon: f64 },
+    /// A US zip code.
+    Zip(String),
+    /// A UK postcode or Canadian postal code, or their outward part, in capitals.
+    Postcode(String),
+    /// An airport by its METAR code.
+    Metar(String),
+    /// An airport by its 3 letter IATA code.
+    Iata(String),
+    /// The location of the caller's IP address.
+    AutoIp,
+    /// The location of an IPv4 or IPv6 address.
+    Ip(IpAddr),
+    /// A location by the id search_locations returned for it.
+    Id(u64),
+    /// Anything else, such as a city name with its region or country.
+    Name(String),
+}
+
+impl Place {
+    /// Parses a location; text that matches none of the other formats is a name.
+    pub fn parse(text: &str) -> Place {
+        let text = text.trim();
+        let lowercase = text.to_lowercase();
+        if lowercase == "auto:ip" {
+            return Place::AutoIp;
+        }
+        if let Some(code) = lowercase.strip_prefix("metar:").map(str::trim).filter(|code| !code.is_empty()) {
+            return Place::Metar(code.to_uppercase());
+        }
+        if let Some(code) = lowercase.strip_prefix("iata:").map(str::trim).filter(|code| !code.is_empty()) {
+            return Place::Iata(code.to_uppercase());
+        }
+        if let Some(id) = lowercase.strip_prefix("id:").and_then(|id| id.trim().parse().ok()) {
+            return Place::Id(id);
+        }
+        if let Ok(ip) = text.parse() {
+            return Place::Ip(ip);
+        }
+        if let Some(coordinates) = parse_coordinates(text) {
+            return coordinates;
+        }
+        if text.len() == 5 && text.bytes().all(|c| c.is_ascii_digit()) {
+            return Place::Zip(text.to_string());
+        }
+        let compact: String = text.split_whitespace().collect::<String>().to_uppercase();
+        if is_postcode(compact.as_bytes()) {
+            return Place::Postcode(compact);
+        }
+        Place::Name(text.to_string())
+    }
+
+    /// Writes the location in its canonical form, with coordinates rounded to `decimals` decimals.
+    pub fn canonical(&self, decimals: u64) -> String {
+        match self {
+            Place::Coordinates { lat, lon } => {
+                let decimals = decimals.min(MAX_COORDINATE_DECIMALS) as usize;
+                format!("{},{}", round(*lat, decimals), round(*lon, decimals))
+            }
+            Place::Zip(zip) => zip.clone(),
+            // The inward part of a full postcode is always its last 3 characters
+            Place::Postcode(code) if code.len() > 4 => format!("{} {}", &code[..code.len() - 3], &code[code.len() - 3..]),
+            Place::Postcode(code) => code.clone(),
+            Place::Metar(code) => format!("metar:{}", code),
+            Place::Iata(code) => format!("iata:{}", code),
+            Place::AutoIp => "auto:ip".to_string(),
+            Place::Ip(ip) => ip.to_string(),
+            Place::Id(id) => format!("id:{}", id),
+            Place::Name(name) => name.split(',')
+                .map(|part| part.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase())
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+}
+
+/// Parses `lat,lon` in decimal degrees, within the ranges of a latitude and a longitude.
+fn parse_coordinates(text: &str) -> Option<Place> {
+    let (lat, lon) = text.split_once(',')?;
+    let lat: f64 = lat.trim().parse().ok()?;
+    let lon: f64 = lon.trim().parse().ok()?;
+    ((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)).then_some(Place::Coordinates { lat, lon })
+}
+
+/// Rounds a coordinate to `decimals` decimals, never writing a negative zero.
+fn round(degrees: f64, decimals: usize) -> String {
+    let text = format!("{:.*}", decimals, degrees);
+    match text.strip_prefix('-') {
+        Some(unsigned) if unsigned.bytes().all(|c| c == b'0' || c == b'.') => unsigned.to_string(),
+        _ => text,
+    }
+}
+
+/// Tells whether `code`, in capitals without spaces, is a UK postcode or a Canadian postal code,
+/// either complete or only their outward part.
+fn is_postcode(code: &[u8]) -> bool {
+    let letter = |c: &u8| c.is_ascii_uppercase();
+    let digit = |c: &u8| c.is_ascii_digit();
+    // A9, A99, AA9, AA99, A9A or AA9A
+    let uk_outward = |code: &[u8]| {
+        let letters = code.iter().take_while(|c| letter(c)).count();
+        (1..=2).contains(&letters) && match &code[letters..] {
+            [d] => digit(d),
+            [d, x] => digit(d) && (digit(x) || letter(x)),
+            _ => false,
+        }
+    };
+    // 9AA
+    let uk_inward = |code: &[u8]| matches!(code, [d, a, b] if digit(d) && letter(a) && letter(b));
+    match code {
+        // A9A or A9A9A9
+        [a, d, b] | [a, d, b, _, _, _] if letter(a) && digit(d) && letter(b)
+            && code[3..].iter().enumerate().all(|(i, c)| if i % 2 == 0 { digit(c) } else { letter(c) }) => true,
+        _ if code.len() >= 5 => uk_outward(&code[..code.len() - 3]) && uk_inward(&code[code.len() - 3..]),
+        _ => uk_outward(code),
+    }
+}
@@ -0,0 +1,508 @@
+//! Typed models of the weatherapi.com responses and of the tool results built from them.
+//!
+//! Every response is deserialized into these structs, so a response that no longer has the
+//! expected shape is reported as a schema error instead of being passed on to the model.
+//! Fields the api leaves out on some plans or endpoints are optional. The doc comments of the
+//! structs the tools return end up as descriptions in their output schemas.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// The place a location resolved to.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct Location {
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub tz_id: Option<String>,
+    pub localtime_epoch: Option<i64>,
+    pub localtime: Option<String>,
+}
+
+/// The weather condition of the current weather, a day or an hour.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Condition {
+    pub text: Option<String>,
+    pub icon: Option<String>,
+    pub code: Option<i64>,
+}
+
+/// The current weather.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Current {
+    pub last_updated: Option<String>,
+    pub last_updated_epoch: Option<i64>,
+    pub temp_c: Option<f64>,
+    pub temp_f: Option<f64>,
+    pub feelslike_c: Option<f64>,
+    pub feelslike_f: Option<f64>,
+    pub is_day: Option<i64>,
+    pub condition: Option<Condition>,
+    pub wind_kph: Option<f64>,
+    pub wind_mph: Option<f64>,
+    pub wind_degree: Option<i64>,
+    pub wind_dir: Option<String>,
+    pub gust_kph: Option<f64>,
+    pub pressure_mb: Option<f64>,
+    pub precip_mm: Option<f64>,
+    pub humidity: Option<i64>,
+    pub cloud: Option<i64>,
+    pub vis_km: Option<f64>,
+    pub uv: Option<f64>,
+    // Only present when requested with `aqi=yes` and `pollen=yes`, and reported by get_air_quality instead
+    #[serde(skip_serializing)]
+    pub air_quality: Option<AirQuality>,
+    #[serde(skip_serializing)]
+    pub pollen: Option<Pollen>,
+}
+
+impl Current {
+    /// Returns the air quality together with the pollen counts, which the api sends alongside it.
+    pub fn air_quality_report(&self) -> AirQuality {
+        AirQuality { pollen: self.pollen.clone(), ..self.air_quality.clone().unwrap_or_default() }
+    }
+}
+
+/// The weather of a single day in a forecast, history or future response.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct ForecastDay {
+    pub date: String,
+    pub date_epoch: Option<i64>,
+    pub day: Day,
+    pub astro: Astro,
+    pub hour: Vec<Hour>,
+}
+
+/// The summary of a day.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Day {
+    pub maxtemp_c: Option<f64>,
+    pub maxtemp_f: Option<f64>,
+    pub mintemp_c: Option<f64>,
+    pub mintemp_f: Option<f64>,
+    pub avgtemp_c: Option<f64>,
+    pub avgtemp_f: Option<f64>,
+    pub maxwind_kph: Option<f64>,
+    pub maxwind_mph: Option<f64>,
+    pub totalprecip_mm: Option<f64>,
+    pub totalsnow_cm: Option<f64>,
+    pub avgvis_km: Option<f64>,
+    pub avghumidity: Option<f64>,
+    pub daily_chance_of_rain: Option<i64>,
+    pub daily_chance_of_snow: Option<i64>,
+    pub condition: Option<Condition>,
+    pub uv: Option<f64>,
+    /// Only present when air quality is requested.
+    pub air_quality: Option<AirQuality>,
+    // Only present when requested with `pollen=yes`, and reported by get_air_quality instead
+    #[serde(skip_serializing)]
+    pub pollen: Option<Pollen>,
+}
+
+impl Day {
+    /// Returns the air quality together with the pollen counts, which the api sends alongside it.
+    pub fn air_quality_report(&self) -> AirQuality {
+        AirQuality { pollen: self.pollen.clone(), ..self.air_quality.clone().unwrap_or_default() }
+    }
+}
+
+/// The sun and moon data of a single day.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Astro {
+    pub sunrise: Option<String>,
+    pub sunset: Option<String>,
+    pub moonrise: Option<String>,
+    pub moonset: Option<String>,
+    pub moon_phase: Option<String>,
+    /// The illuminated percentage of the moon.
+    // Older responses send the illumination as a string
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub moon_illumination: Option<f64>,
+    pub is_moon_up: Option<i64>,
+    pub is_sun_up: Option<i64>,
+}
+
+/// The weather of a single hour.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Hour {
+    pub time: Option<String>,
+    pub time_epoch: Option<i64>,
+    pub temp_c: Option<f64>,
+    pub temp_f: Option<f64>,
+    pub feelslike_c: Option<f64>,
+    pub is_day: Option<i64>,
+    pub condition: Option<Condition>,
+    pub wind_kph: Option<f64>,
+    pub wind_dir: Option<String>,
+    pub gust_kph: Option<f64>,
+    pub precip_mm: Option<f64>,
+    pub humidity: Option<i64>,
+    pub cloud: Option<i64>,
+    pub chance_of_rain: Option<i64>,
+    pub chance_of_snow: Option<i64>,
+    pub vis_km: Option<f64>,
+    pub uv: Option<f64>,
+    /// Only present when air quality is requested.
+    pub air_quality: Option<AirQuality>,
+}
+
+/// The pollutant concentrations in μg/m3 and the air quality indexes.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct AirQuality {
+    pub co: Option<f64>,
+    pub no2: Option<f64>,
+    pub o3: Option<f64>,
+    pub so2: Option<f64>,
+    pub pm2_5: Option<f64>,
+    pub pm10: Option<f64>,
+    /// From 1 (good) to 6 (hazardous).
+    #[serde(rename = "us-epa-index")]
+    pub us_epa_index: Option<i64>,
+    /// From 1 (low) to 10 (very high).
+    #[serde(rename = "gb-defra-index")]
+    pub gb_defra_index: Option<i64>,
+    /// Only reported by get_air_quality, on plans that include pollen.
+    // The api sends pollen next to the air quality, see `Current::air_quality_report`
+    pub pollen: Option<Pollen>,
+}
+
+/// Pollen counts in grains per cubic meter.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Pollen {
+    #[serde(alias = "Hazel")]
+    pub hazel: Option<f64>,
+    #[serde(alias = "Alder")]
+    pub alder: Option<f64>,
+    #[serde(alias = "Birch")]
+    pub birch: Option<f64>,
+    #[serde(alias = "Oak")]
+    pub oak: Option<f64>,
+    #[serde(alias = "Grass")]
+    pub grass: Option<f64>,
+    #[serde(alias = "Mugwort")]
+    pub mugwort: Option<f64>,
+    #[serde(alias = "Ragweed")]
+    pub ragweed: Option<f64>,
+}
+
+/// A government weather alert.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Alert {
+    pub headline: Option<String>,
+    pub event: Option<String>,
+    pub msgtype: Option<String>,
+    pub severity: Option<String>,
+    pub urgency: Option<String>,
+    pub certainty: Option<String>,
+    pub category: Option<String>,
+    pub areas: Option<String>,
+    pub effective: Option<String>,
+    pub expires: Option<String>,
+    pub desc: Option<String>,
+    pub instruction: Option<String>,
+    pub note: Option<String>,
+}
+
+/// The sea conditions of a single hour.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct MarineHour {
+    pub time: Option<String>,
+    pub sig_ht_mt: Option<f64>,
+    pub swell_ht_mt: Option<f64>,
+    pub swell_dir_16_point: Option<String>,
+    pub swell_period_secs: Option<f64>,
+    pub water_temp_c: Option<f64>,
+}
+
+/// A high or low tide.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct Tide {
+    pub tide_time: Option<String>,
+    // The api sends tide heights as strings such as "1.50"
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub tide_height_mt: Option<f64>,
+    pub tide_type: Option<String>,
+}
+
+/// A football, cricket or golf event.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct SportsEvent {
+    #[serde(rename = "match")]
+    pub match_name: Option<String>,
+    pub tournament: Option<String>,
+    pub stadium: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    pub start: Option<String>,
+}
+
+/// The current weather at a location.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct CurrentWeather {
+    pub location: Location,
+    pub current: Current,
+}
+
+/// A forecast.json, history.json or future.json response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForecastResponse {
+    pub location: Location,
+    pub forecast: ForecastDays,
+    /// Only present when requested with `alerts=yes`.
+    pub alerts: Option<Alerts>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ForecastDays {
+    pub forecastday: Vec<ForecastDay>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Alerts {
+    #[serde(default)]
+    pub alert: Vec<Alert>,
+}
+
+/// The weather of a location per day.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Forecast {
+    pub location: Location,
+    pub forecastday: Vec<ForecastDay>,
+    /// Only present when alerts are requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<Vec<Alert>>,
+}
+
+impl From<ForecastResponse> for Forecast {
+    fn from(response: ForecastResponse) -> Forecast {
+        Forecast {
+            location: response.location,
+            forecastday: response.forecast.forecastday,
+            alerts: response.alerts.map(|alerts| alerts.alert),
+        }
+    }
+}
+
+/// A marine.json response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct MarineResponse {
+    pub location: Location,
+    pub forecast: MarineDays,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MarineDays {
+    pub forecastday: Vec<MarineResponseDay>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MarineResponseDay {
+    pub date: Option<String>,
+    pub day: MarineDay,
+    pub hour: Vec<MarineHour>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MarineDay {
+    /// Only present when requested with `tides=yes`.
+    #[serde(default)]
+    pub tides: Vec<Tides>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tides {
+    #[serde(default)]
+    pub tide: Vec<Tide>,
+}
+
+/// The sea conditions and tides of a location per day.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Marine {
+    pub location: Location,
+    pub forecastday: Vec<MarineForecastDay>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct MarineForecastDay {
+    pub date: Option<String>,
+    pub tides: Vec<Tide>,
+    pub hour: Vec<MarineHour>,
+}
+
+impl From<MarineResponse> for Marine {
+    fn from(response: MarineResponse) -> Marine {
+        let forecastday = response.forecast.forecastday.into_iter().map(|day| MarineForecastDay {
+            date: day.date,
+            tides: day.day.tides.into_iter().flat_map(|tides| tides.tide).collect(),
+            hour: day.hour,
+        }).collect();
+        Marine { location: response.location, forecastday }
+    }
+}
+
+/// An astronomy.json response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AstronomyResponse {
+    pub location: Location,
+    pub astronomy: AstronomyDay,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AstronomyDay {
+    pub astro: Astro,
+}
+
+/// The sun and moon data of a location on a day.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Astronomy {
+    pub location: Location,
+    pub astro: Astro,
+}
+
+impl From<AstronomyResponse> for Astronomy {
+    fn from(response: AstronomyResponse) -> Astronomy {
+        Astronomy { location: response.location, astro: response.astronomy.astro }
+    }
+}
+
+/// A place matching the search.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct SearchLocation {
+    pub id: i64,
+    /// The location that selects exactly this place in the other tools.
+    #[serde(skip_deserializing)]
+    pub location: String,
+    pub name: Option<String>,
+    pub region: Option<String>,
+    pub country: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+}
+
+/// The places matching a search.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Search {
+    pub locations: Vec<SearchLocation>,
+}
+
+/// Where an IP address is located.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct IpLookup {
+    pub ip: String,
+    #[serde(rename = "type")]
+    pub ip_type: Option<String>,
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub country_code: Option<String>,
+    pub country_name: Option<String>,
+    pub continent_name: Option<String>,
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub tz_id: Option<String>,
+    pub localtime: Option<String>,
+    pub localtime_epoch: Option<i64>,
+}
+
+/// A timezone.json response, of which get_timezone returns the location.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TimezoneResponse {
+    pub location: Location,
+}
+
+/// An alerts.json response.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertsResponse {
+    pub location: Location,
+    #[serde(default)]
+    pub alerts: Alerts,
+}
+
+/// The active weather alerts of a location.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct AlertsReport {
+    pub location: Location,
+    pub alerts: Vec<Alert>,
+}
+
+impl From<AlertsResponse> for AlertsReport {
+    fn from(response: AlertsResponse) -> AlertsReport {
+        AlertsReport { location: response.location, alerts: response.alerts.alert }
+    }
+}
+
+/// A current.json or, when days are requested, forecast.json response asked for `aqi=yes` and `pollen=yes`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AirQualityResponse {
+    pub location: Location,
+    pub current: Current,
+    pub forecast: Option<ForecastDays>,
+}
+
+/// The current and, when days are requested, forecast air quality of a location.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct AirQualityReport {
+    pub location: Location,
+    pub current: AirQuality,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forecastday: Option<Vec<AirQualityDay>>,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct AirQualityDay {
+    pub date: String,
+    pub air_quality: AirQuality,
+}
+
+impl From<AirQualityResponse> for AirQualityReport {
+    fn from(response: AirQualityResponse) -> AirQualityReport {
+        AirQualityReport {
+            current: response.current.air_quality_report(),
+            forecastday: response.forecast.map(|forecast| forecast.forecastday.iter().map(|day| AirQualityDay {
+                date: day.date.clone(),
+                air_quality: day.day.air_quality_report(),
+            }).collect()),
+            location: response.location,
+        }
+    }
+}
+
+/// The upcoming sports events near a location.
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct SportsEvents {
+    #[serde(default)]
+    pub football: Vec<SportsEvent>,
+    #[serde(default)]
+    pub cricket: Vec<SportsEvent>,
+    #[serde(default)]
+    pub golf: Vec<SportsEvent>,
+}
+
+/// The body of a failed weatherapi.com request.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ApiError,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiError {
+    pub code: Option<i64>,
+    pub message: Option<String>,
+}
+
+/// Deserializes a number the api sometimes sends as a string.
+fn number_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<f64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(f64),
+        String(String),
+    }
+    match Option::<NumberOrString>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(NumberOrString::Number(number)) => Ok(Some(number)),
+        Some(NumberOrString::String(text)) if text.trim().is_empty() => Ok(None),
+        Some(NumberOrString::String(text)) => text.trim().parse().map(Some).map_err(serde::de::Error::custom),
+    }
+}
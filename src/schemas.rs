@@ -0,0 +1,162 @@
+//! JSON schemas advertised as the input and output of the tools.
+//!
+//! The schemas are generated from the argument structs of `arguments.rs` and the result
+//! structs of `models.rs`, the same types `call_tool` deserializes and serializes, and
+//! `call_tool` checks the arguments against them before deserializing.
+
+use schemars::{generate::SchemaSettings, JsonSchema};
+
+use crate::date::Date;
+
+/// The schema of the json arguments that deserialize into `T`.
+pub fn input_schema<T: JsonSchema>() -> String {
+    SchemaSettings::draft2020_12()
+        .for_deserialize()
+        .into_generator()
+        .into_root_schema_for::<T>()
+        .as_value()
+        .to_string()
+}
+
+/// The schema of the json result `T` serializes into.
+pub fn output_schema<T: JsonSchema>() -> String {
+    SchemaSettings::draft2020_12()
+        .for_serialize()
+        .into_generator()
+        .into_root_schema_for::<T>()
+        .as_value()
+        .to_string()
+}
+
+/// Checks json arguments against the input schema of `T`, naming the offending field and its
+/// expected type when they do not match.
+pub fn check_arguments<T: JsonSchema>(arguments: &serde_json::Value) -> Result<(), String> {
+    let schema = SchemaSettings::draft2020_12()
+        .for_deserialize()
+        .into_generator()
+        .into_root_schema_for::<T>();
+    check_value(arguments, schema.as_value(), schema.as_value(), "")
+}
+
+/// Checks a value against the part of JSON Schema the generated schemas use.
+fn check_value(value: &serde_json::Value, schema: &serde_json::Value, root: &serde_json::Value, path: &str) -> Result<(), String> {
+    if let Some(reference) = schema.get("$ref").and_then(|r| r.as_str()) {
+        let definition = reference.strip_prefix("#/$defs/")
+            .and_then(|name| root["$defs"].get(name))
+            .ok_or_else(|| format!("{} refers to the unknown schema {}", field(path), reference))?;
+        return check_value(value, definition, root, path);
+    }
+    if let Some(options) = schema.get("anyOf").and_then(|o| o.as_array()) {
+        let mut errors = options.iter().filter_map(|option| check_value(value, option, root, path).err());
+        return match errors.next() {
+            Some(error) if errors.count() + 1 == options.len() => Err(error),
+            _ => Ok(()),
+        };
+    }
+
+    let allowed = types(schema);
+    if !allowed.is_empty() && !allowed.iter().any(|t| has_type(value, t)) {
+        return Err(format!("{} must be {}, not {}", field(path), describe(schema), value));
+    }
+    if let (Some(n), Some(minimum)) = (value.as_f64(), schema.get("minimum").and_then(|m| m.as_f64())) {
+        if n < minimum {
+            return Err(format!("{} must be {} of at least {}, not {}", field(path), expected(&allowed), minimum, value));
+        }
+    }
+    if let (Some(n), Some(maximum)) = (value.as_f64(), schema.get("maximum").and_then(|m| m.as_f64())) {
+        if n > maximum {
+            return Err(format!("{} must be {} of at most {}, not {}", field(path), expected(&allowed), maximum, value));
+        }
+    }
+    if let (Some(text), Some("date")) = (value.as_str(), schema.get("format").and_then(|f| f.as_str())) {
+        if Date::parse(text).is_none() {
+            return Err(format!("{} must be a yyyy-MM-dd date, not {}", field(path), value));
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        let properties = schema.get("properties").and_then(|p| p.as_object());
+        for required in schema["required"].as_array().into_iter().flatten().filter_map(|r| r.as_str()) {
+            if object.get(required).is_none_or(|v| v.is_null()) {
+                return Err(match properties.and_then(|p| p.get(required)).filter(|p| !types(p).is_empty()) {
+                    Some(property) => format!("{} is required and must be {}", field(&child(path, required)), describe(property)),
+                    None => format!("{} is required", field(&child(path, required))),
+                });
+            }
+        }
+        for (name, property) in properties.into_iter().flatten() {
+            if let Some(v) = object.get(name) {
+                check_value(v, property, root, &child(path, name))?;
+            }
+        }
+    }
+    if let (Some(items), Some(item_schema)) = (value.as_array(), schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            check_value(item, item_schema, root, &format!("{}[{}]", path, i))?;
+        }
+    }
+    Ok(())
+}
+
+fn types(schema: &serde_json::Value) -> Vec<&str> {
+    match &schema["type"] {
+        serde_json::Value::String(t) => vec![t.as_str()],
+        serde_json::Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn has_type(value: &serde_json::Value, t: &str) -> bool {
+    match t {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    }
+}
+
+/// Describes the value a schema expects.
+fn describe(schema: &serde_json::Value) -> String {
+    match schema.get("format").and_then(|f| f.as_str()) {
+        Some("date") => "a yyyy-MM-dd date".to_string(),
+        _ => expected(&types(schema)),
+    }
+}
+
+/// Describes the expected types, leaving out null as that only marks an argument as optional.
+fn expected(types: &[&str]) -> String {
+    let described: Vec<&str> = types.iter().filter_map(|t| match *t {
+        "boolean" => Some("a boolean"),
+        "integer" => Some("an integer"),
+        "number" => Some("a number"),
+        "string" => Some("a string"),
+        "array" => Some("an array"),
+        "object" => Some("an object"),
+        _ => None,
+    }).collect();
+    if described.is_empty() {
+        "null".to_string()
+    } else {
+        described.join(" or ")
+    }
+}
+
+fn child(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+fn field(path: &str) -> String {
+    if path.is_empty() {
+        "the arguments".to_string()
+    } else {
+        path.to_string()
+    }
+}
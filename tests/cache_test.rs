@@ -0,0 +1,304 @@
+mod common;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use common::exports::wasix::mcp::router::{CallToolResult, Content, ToolError};
+use common::server::{instantiate, json_content, large_forecast, serve, Script};
+use common::{call, TestState, Upstream};
+use serde_json::json;
+use wasmtime::Store;
+
+#[test]
+fn test_responses_are_cached() {
+    let script = Script::default();
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    let requests = |store: &Store<TestState>| store.data().requests.load(Ordering::SeqCst);
+
+    let fetched = call(&mut store, &router, "get_forecast", json!({ "location": "London, UK", "days": 14 })).expect("get_forecast failed");
+    assert_eq!(fetched.content.len(), 1);
+    assert!(matches!(&fetched.content[0], Content::Text(text) if text.annotations.is_none()));
+
+    // The same location, however it is spelled, is served from the cache and says so
+    let cached = call(&mut store, &router, "get_forecast", json!({ "location": "  london,uk ", "days": 14 })).expect("get_forecast failed");
+    assert_eq!(requests(&store), 1);
+    assert_eq!(json_content(&cached), json_content(&fetched));
+    match &cached.content[0] {
+        Content::Text(text) => {
+            let timestamp = text.annotations.as_ref().and_then(|annotations| annotations.timestamp.clone()).expect("no timestamp");
+            assert!(timestamp.ends_with('Z') && timestamp.contains('T'), "unexpected timestamp {}", timestamp);
+        }
+        _ => panic!("Not right content"),
+    }
+    assert!(matches!(&cached.content[1], Content::Text(text) if text.text.starts_with("Served from the cache")));
+
+    // Other arguments, endpoints and time sensitive endpoints are fetched
+    call(&mut store, &router, "get_forecast", json!({ "location": "London, UK", "days": 13 })).expect("get_forecast failed");
+    assert_eq!(requests(&store), 2);
+    call(&mut store, &router, "get_astronomy", json!({ "location": "London, UK" })).ok();
+    assert_eq!(requests(&store), 3);
+    call(&mut store, &router, "get_timezone", json!({ "location": "London, UK" })).ok();
+    call(&mut store, &router, "get_timezone", json!({ "location": "London, UK" })).ok();
+    assert_eq!(requests(&store), 5);
+
+    // History never changes once its days are over, until then it is cached for 5 minutes
+    for _ in 0..2 {
+        call(&mut store, &router, "get_history", json!({ "location": "London", "dt": "2025-10-01" })).expect("get_history failed");
+        call(&mut store, &router, "get_history", json!({ "location": "London", "dt": "2999-01-01" })).expect("get_history failed");
+    }
+    assert_eq!(requests(&store), 7);
+    store.data().skipped_ns.store(6 * 60 * 1_000_000_000, Ordering::SeqCst);
+    call(&mut store, &router, "get_history", json!({ "location": "London", "dt": "2025-10-01" })).expect("get_history failed");
+    assert_eq!(requests(&store), 7);
+    call(&mut store, &router, "get_history", json!({ "location": "London", "dt": "2999-01-01" })).expect("get_history failed");
+    assert_eq!(requests(&store), 8);
+}
+
+#[test]
+fn test_only_the_responses_a_result_is_built_from_are_noted() {
+    let search = br#"[{"id":2801268,"name":"Paris","region":"Ile-de-France","country":"France","lat":48.87,"lon":2.33}]"#;
+    let script = Script { body: search.to_vec(), ..Script::default() };
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    call(&mut store, &router, "search_locations", json!({ "query": "Paris" })).expect("search_locations failed");
+
+    // The disambiguating search is served from the cache, but the forecast is fetched and is not noted as cached
+    let (server, requests) = serve(Script::default());
+    store.data_mut().upstream = Upstream::Server(server);
+    store.data_mut().requests = requests;
+    let fetched = call(&mut store, &router, "get_forecast", json!({ "location": "Paris", "days": 14, "disambiguate": true }))
+        .expect("get_forecast failed");
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 1);
+    assert_eq!(fetched.content.len(), 1, "fresh data was noted as cached: {:?}", fetched.content);
+    assert!(matches!(&fetched.content[0], Content::Text(text) if text.annotations.is_none()));
+}
+
+/// Built with `--features keyvalue`, the responses one instance stored are served to the next instance,
+/// unless they are corrupt or past keeping.
+#[cfg(feature = "keyvalue")]
+#[test]
+fn test_stored_responses_outlive_the_instance() {
+    let key_values = common::KeyValueStore::default();
+    let instance = |key_values: &common::KeyValueStore| {
+        let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], Script::default());
+        store.data_mut().key_value_store = key_values.clone();
+        (store, router)
+    };
+    let requests = |store: &Store<TestState>| store.data().requests.load(Ordering::SeqCst);
+    let arguments = json!({ "location": "London", "days": 14 });
+
+    let (mut store, router) = instance(&key_values);
+    call(&mut store, &router, "get_forecast", arguments.clone()).expect("get_forecast failed");
+    assert_eq!(requests(&store), 1);
+
+    let (mut store, router) = instance(&key_values);
+    let cached = call(&mut store, &router, "get_forecast", arguments.clone()).expect("get_forecast failed");
+    assert_eq!(requests(&store), 0);
+    assert!(matches!(&cached.content[1], Content::Text(text) if text.text.starts_with("Served from the cache")));
+
+    // A corrupt stored response is fetched again and replaced
+    let stored_key = key_values.values.lock().unwrap().keys().find(|key| key.starts_with("weather-api//v1/forecast.json")).cloned()
+        .expect("the forecast was not stored");
+    key_values.values.lock().unwrap().insert(stored_key.clone(), br#"{"body":"#.to_vec());
+    let (mut store, router) = instance(&key_values);
+    call(&mut store, &router, "get_forecast", arguments.clone()).expect("get_forecast failed");
+    assert_eq!(requests(&store), 1);
+    let mut stored: serde_json::Value = serde_json::from_slice(&key_values.values.lock().unwrap()[&stored_key]).expect("the forecast was not stored again");
+
+    // So is a stored response past keeping
+    stored["expires_at_seconds"] = json!(1);
+    stored["kept_until_seconds"] = json!(2);
+    key_values.values.lock().unwrap().insert(stored_key.clone(), stored.to_string().into_bytes());
+    let (mut store, router) = instance(&key_values);
+    call(&mut store, &router, "get_forecast", arguments.clone()).expect("get_forecast failed");
+    assert_eq!(requests(&store), 1);
+    let stored: serde_json::Value = serde_json::from_slice(&key_values.values.lock().unwrap()[&stored_key]).expect("the forecast was not stored again");
+    assert!(stored["kept_until_seconds"].as_u64() > Some(2), "the expired forecast was not replaced: {}", stored);
+}
+
+/// Built with `--features keyvalue`, the stored responses are bounded like the in-memory cache.
+#[cfg(feature = "keyvalue")]
+#[test]
+fn test_stored_responses_are_bounded() {
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], Script::default());
+    let key_values = store.data().key_value_store.clone();
+    let place = |i: u64| format!("weather-api//v1/current.json&q=place {}", i);
+
+    // A full index, and one more response that is past keeping
+    let mut index = serde_json::Map::new();
+    for i in 0..256 {
+        key_values.values.lock().unwrap().insert(place(i), b"{}".to_vec());
+        index.insert(place(i), json!({ "fetched_at_seconds": 1000 + i, "kept_until_seconds": null }));
+    }
+    key_values.values.lock().unwrap().insert("weather-api//v1/current.json&q=expired".to_string(), b"{}".to_vec());
+    index.insert("weather-api//v1/current.json&q=expired".to_string(), json!({ "fetched_at_seconds": 2000, "kept_until_seconds": 2001 }));
+    key_values.values.lock().unwrap().insert("weather-api/index".to_string(), serde_json::Value::Object(index).to_string().into_bytes());
+
+    // Storing a response deletes the one past keeping and then the oldest
+    call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })).expect("get_forecast failed");
+    {
+        let values = key_values.values.lock().unwrap();
+        assert!(!values.contains_key("weather-api//v1/current.json&q=expired"));
+        assert!(!values.contains_key(&place(0)));
+        assert!(values.contains_key(&place(1)));
+        let index: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&values["weather-api/index"]).expect("the index is not a map");
+        assert_eq!(index.len(), 256);
+        assert!(index.keys().any(|key| key.starts_with("weather-api//v1/forecast.json")));
+    }
+
+    // Without a readable index, the responses it lost track of are deleted, but not the keys of others sharing the bucket
+    key_values.values.lock().unwrap().insert("weather-api/index".to_string(), b"[".to_vec());
+    key_values.values.lock().unwrap().insert("another-component/key".to_string(), b"value".to_vec());
+    call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 13 })).expect("get_forecast failed");
+    let values = key_values.values.lock().unwrap();
+    assert_eq!(values.keys().filter(|key| key.starts_with("weather-api/")).count(), 2, "{:?}", values.keys().collect::<Vec<_>>());
+    assert!(values.contains_key("another-component/key"));
+}
+
+#[test]
+fn test_stale_responses_are_served_when_the_api_fails() {
+    let script = Script::default();
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    let arguments = json!({ "location": "London", "days": 14 });
+    let fetched = call(&mut store, &router, "get_forecast", arguments.clone()).expect("get_forecast failed");
+    let fail_with = |store: &mut Store<TestState>, failures: &'static [(&'static str, &'static str)]| {
+        let (server, requests) = serve(Script { body: Vec::new(), failures, ..Script::default() });
+        store.data_mut().upstream = Upstream::Server(server);
+        store.data_mut().requests = requests;
+    };
+    let stale_note = |result: &CallToolResult| match &result.content[1] {
+        Content::Text(text) => text.text.clone(),
+        _ => panic!("Not right content"),
+    };
+
+    // A forecast stays fresh for 30 minutes; after that a failing api gets a single attempt before the stale forecast is served
+    store.data().skipped_ns.store(31 * 60 * 1_000_000_000, Ordering::SeqCst);
+    fail_with(&mut store, &[("503 Service Unavailable", ""); 3]);
+    let stale = call(&mut store, &router, "get_forecast", arguments.clone()).expect("the stale forecast was not served");
+    assert_eq!(json_content(&stale), json_content(&fetched));
+    assert!(stale_note(&stale).starts_with("Stale: served from the cache because weatherapi.com failed"), "unexpected note: {}", stale_note(&stale));
+    assert!(stale_note(&stale).contains("seconds ago"), "the note does not give the age: {}", stale_note(&stale));
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 1);
+
+    // So is an exceeded quota, but not a client error
+    fail_with(&mut store, &[("403 Forbidden", r#"{"error":{"code":2007,"message":"API key has exceeded calls per month quota."}}"#)]);
+    let stale = call(&mut store, &router, "get_forecast", arguments.clone()).expect("the stale forecast was not served");
+    assert!(stale_note(&stale).contains("quota"), "unexpected note: {}", stale_note(&stale));
+    fail_with(&mut store, &[("400 Bad Request", r#"{"error":{"code":1006,"message":"No matching location found."}}"#)]);
+    let result = call(&mut store, &router, "get_forecast", arguments.clone());
+    assert!(matches!(result, Err(ToolError::InvalidParameters(_))), "got {:?}", result);
+
+    // The stale policy limits how long after it expired a response is served, per endpoint
+    store.data_mut().secrets.push(("WEATHER_API_MAX_STALE_SECONDS", "current=60, forecast=300"));
+    fail_with(&mut store, &[("503 Service Unavailable", ""); 3]);
+    call(&mut store, &router, "get_forecast", arguments.clone()).expect("the stale forecast was not served");
+    store.data().skipped_ns.store(36 * 60 * 1_000_000_000, Ordering::SeqCst);
+    fail_with(&mut store, &[("503 Service Unavailable", ""); 3]);
+    let result = call(&mut store, &router, "get_forecast", arguments.clone());
+    assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 3);
+
+    store.data_mut().secrets.pop();
+    store.data_mut().secrets.push(("WEATHER_API_MAX_STALE_SECONDS", "weather=60"));
+    match call(&mut store, &router, "get_forecast", arguments) {
+        Err(ToolError::ExecutionError(message)) => assert!(message.contains("WEATHER_API_MAX_STALE_SECONDS") && message.contains("current, alerts"), "unexpected message: {}", message),
+        other => panic!("expected an ExecutionError, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_stale_responses_are_served_while_revalidating() {
+    let secrets = vec![("WEATHER_API_KEY", "key"), ("WEATHER_API_STALE_WHILE_REVALIDATE", "current, forecast")];
+    let (mut store, router) = instantiate(secrets, Script::default());
+    let arguments = json!({ "location": "London", "days": 14 });
+    let fetched = call(&mut store, &router, "get_forecast", arguments.clone()).expect("get_forecast failed");
+    let note = |result: &CallToolResult| match &result.content[1] {
+        Content::Text(text) => text.text.clone(),
+        _ => panic!("Not right content"),
+    };
+
+    // An expired forecast is served at once, and a later call gets the fresh one fetched meanwhile
+    store.data().skipped_ns.store(31 * 60 * 1_000_000_000, Ordering::SeqCst);
+    let fresh = String::from_utf8(large_forecast()).unwrap().replace("15.2", "16.3").into_bytes();
+    let (server, requests) = serve(Script { body: fresh, ..Script::default() });
+    store.data_mut().upstream = Upstream::Server(server);
+    store.data_mut().requests = requests;
+    let stale = call(&mut store, &router, "get_forecast", arguments.clone()).expect("the stale forecast was not served");
+    assert_eq!(json_content(&stale), json_content(&fetched));
+    assert!(note(&stale).starts_with("Stale: served from the cache while weatherapi.com is asked for a fresh response"), "unexpected note: {}", note(&stale));
+
+    let started = Instant::now();
+    let revalidated = loop {
+        thread::sleep(Duration::from_millis(50));
+        let result = call(&mut store, &router, "get_forecast", arguments.clone()).expect("get_forecast failed");
+        if !note(&result).starts_with("Stale") || started.elapsed() > Duration::from_secs(10) {
+            break result;
+        }
+    };
+    assert!(note(&revalidated).starts_with("Served from the cache"), "unexpected note: {}", note(&revalidated));
+    assert!(json_content(&revalidated).to_string().contains("16.3"), "the fresh forecast was not cached");
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 1);
+
+    // A failed revalidation keeps serving the stale forecast
+    store.data().skipped_ns.store(62 * 60 * 1_000_000_000, Ordering::SeqCst);
+    let (server, requests) = serve(Script { body: Vec::new(), failures: &[("503 Service Unavailable", ""); 3], ..Script::default() });
+    store.data_mut().upstream = Upstream::Server(server);
+    store.data_mut().requests = requests;
+    call(&mut store, &router, "get_forecast", arguments.clone()).expect("the stale forecast was not served");
+    while store.data().requests.load(Ordering::SeqCst) == 0 && started.elapsed() < Duration::from_secs(20) {
+        thread::sleep(Duration::from_millis(50));
+    }
+    thread::sleep(Duration::from_millis(200));
+    let stale = call(&mut store, &router, "get_forecast", arguments).expect("the stale forecast was not served");
+    assert!(note(&stale).starts_with("Stale"), "unexpected note: {}", note(&stale));
+    assert_eq!(json_content(&stale), json_content(&revalidated));
+}
+
+#[test]
+fn test_locations_share_cache_entries_in_canonical_form() {
+    let script = Script::default();
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    let requests = |store: &Store<TestState>| store.data().requests.load(Ordering::SeqCst);
+    let same_places = [
+        ("Paris", " paris "),
+        ("Paris, France", "PARIS ,  france"),
+        ("48.8567,2.3508", "48.86, 2.35"),
+        ("-0.001,0.004", "0,0"),
+        ("10001", " 10001 "),
+        ("SW1A 1AA", "sw1a1aa"),
+        ("SW1", "sw1"),
+        ("G2J 1A1", "g2j1a1"),
+        ("metar:EGLL", "METAR:egll"),
+        ("iata:DXB", "IATA: dxb"),
+        ("auto:ip", "AUTO:IP"),
+        ("2001:db8::1", "2001:0db8:0000:0000:0000:0000:0000:0001"),
+        ("id:2801268", "ID:2801268"),
+    ];
+    for (count, (first, second)) in same_places.iter().enumerate() {
+        call(&mut store, &router, "get_forecast", json!({ "location": first })).expect("get_forecast failed");
+        let cached = call(&mut store, &router, "get_forecast", json!({ "location": second })).expect("get_forecast failed");
+        assert_eq!(requests(&store), count + 1, "{} and {} did not share a cache entry", first, second);
+        assert!(matches!(&cached.content[1], Content::Text(text) if text.text.starts_with("Served from the cache")));
+    }
+
+    // A finer grid tells the coordinates apart
+    store.data_mut().secrets.push(("WEATHER_API_COORDINATE_DECIMALS", "4"));
+    call(&mut store, &router, "get_forecast", json!({ "location": "48.8567,2.3508" })).expect("get_forecast failed");
+    call(&mut store, &router, "get_forecast", json!({ "location": "48.85671,2.35081" })).expect("get_forecast failed");
+    assert_eq!(requests(&store), same_places.len() + 1);
+    call(&mut store, &router, "get_forecast", json!({ "location": "48.86,2.35" })).expect("get_forecast failed");
+    assert_eq!(requests(&store), same_places.len() + 2);
+
+    // Only the cache key is rounded, the api is asked about the coordinates as given
+    let (mut store, router) = common::instantiate(vec![("WEATHER_API_KEY", "key")], Upstream::Responder(|path| {
+        assert!(!path.contains("q=48.86%2C2.35"), "the coordinates were rounded in {}", path);
+        Ok((200, large_forecast()))
+    }), Arc::default());
+    call(&mut store, &router, "get_forecast", json!({ "location": "48.8567,2.3508" })).expect("get_forecast failed");
+
+    // The logs give the canonical location
+    call(&mut store, &router, "get_weather", json!({ "location": " PARIS ,  france" })).expect("get_weather failed");
+    let logged = store.data().logs.iter().filter(|message| message.starts_with("called:")).map(String::as_str).collect::<Vec<_>>();
+    assert!(logged[0].contains("48.86,2.35") && !logged[0].contains("48.8567"), "unexpected log: {}", logged[0]);
+    assert!(logged[1].contains("paris,france"), "unexpected log: {}", logged[1]);
+}
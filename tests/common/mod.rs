@@ -0,0 +1,318 @@
+//! The host the offline tests run the component in, with the weather api replaced by a stand-in.
+
+// Every test file uses its own part of the harness
+#![allow(dead_code)]
+
+pub mod server;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use bytes::Bytes;
+use exports::wasix::mcp::router::{CallToolResult, Content, Tool, ToolError, Value};
+use http_body_util::{BodyExt, Full};
+use wasix::mcp::secrets_store::{HostSecret, Secret, SecretValue, SecretsError};
+use wasmtime::component::{bindgen, Component, Linker, Resource};
+use wasmtime::{Config, Engine, Store};
+use wasmtime_wasi::{HostMonotonicClock, HostWallClock, IoView, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+use wasmtime_wasi_http::types::{default_send_request, HostFutureIncomingResponse, IncomingResponse, OutgoingRequestConfig};
+use wasmtime_wasi_http::{HttpResult, WasiHttpCtx, WasiHttpView};
+use self::wasi::keyvalue::store::{self as keyvalue, Bucket, HostBucket, KeyResponse};
+use self::wasi::logging::logging;
+
+// The keyvalue world only adds an import, so it also runs the component built without the keyvalue feature
+bindgen!({
+    world: "mcp-secrets-keyvalue",
+});
+
+/// Answers every outgoing request with a status and body, or fails it with a transport error.
+pub type Responder = fn(&str) -> Result<(u16, Vec<u8>), ErrorCode>;
+
+/// Where the weather api requests of the component go instead of weatherapi.com.
+#[derive(Clone, Copy)]
+pub enum Upstream {
+    /// Every request is answered in-process by a function of its path.
+    Responder(Responder),
+    /// Every request is sent to a local stand-in server over plain http.
+    Server(SocketAddr),
+}
+
+/// An in-memory wasi:keyvalue store, shared by the component instances of a test like a host's store.
+#[derive(Debug, Clone, Default)]
+pub struct KeyValueStore {
+    pub values: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+/// A host that never reaches the network.
+pub struct TestState {
+    pub secrets: Vec<(&'static str, &'static str)>,
+    pub upstream: Upstream,
+    /// The number of requests the stand-in server received.
+    pub requests: Arc<AtomicUsize>,
+    /// The time the component's clocks have been moved ahead by, in nanoseconds.
+    pub skipped_ns: Arc<AtomicU64>,
+    /// The keyvalue store, empty for every new instance unless a test shares one.
+    pub key_value_store: KeyValueStore,
+    /// The messages the component logged.
+    pub logs: Vec<String>,
+    table: ResourceTable,
+    ctx: WasiCtx,
+    http: WasiHttpCtx,
+}
+
+impl HostSecret for TestState {
+    fn drop(&mut self, _rep: Resource<Secret>) -> wasmtime::Result<()> {
+        Ok(())
+    }
+}
+
+impl wasix::mcp::secrets_store::Host for TestState {
+    fn get(&mut self, key: String) -> Result<Resource<Secret>, SecretsError> {
+        match self.secrets.iter().position(|(name, _)| *name == key) {
+            Some(index) => Ok(Resource::<Secret>::new_own(index as u32)),
+            None => Err(SecretsError::NotFound),
+        }
+    }
+
+    fn reveal(&mut self, s: Resource<Secret>) -> SecretValue {
+        SecretValue { secret: self.secrets[s.rep() as usize].1.to_string() }
+    }
+}
+
+impl logging::Host for TestState {
+    fn log(&mut self, _level: logging::Level, _context: String, message: String) {
+        self.logs.push(message);
+    }
+}
+
+impl keyvalue::Host for KeyValueStore {
+    fn open(&mut self, _identifier: String) -> Result<Resource<Bucket>, keyvalue::Error> {
+        Ok(Resource::<Bucket>::new_own(1))
+    }
+}
+
+impl HostBucket for KeyValueStore {
+    fn get(&mut self, _bucket: Resource<Bucket>, key: String) -> Result<Option<Vec<u8>>, keyvalue::Error> {
+        Ok(self.values.lock().unwrap().get(&key).cloned())
+    }
+
+    fn set(&mut self, _bucket: Resource<Bucket>, key: String, value: Vec<u8>) -> Result<(), keyvalue::Error> {
+        self.values.lock().unwrap().insert(key, value);
+        Ok(())
+    }
+
+    fn delete(&mut self, _bucket: Resource<Bucket>, key: String) -> Result<(), keyvalue::Error> {
+        self.values.lock().unwrap().remove(&key);
+        Ok(())
+    }
+
+    fn exists(&mut self, _bucket: Resource<Bucket>, key: String) -> Result<bool, keyvalue::Error> {
+        Ok(self.values.lock().unwrap().contains_key(&key))
+    }
+
+    fn list_keys(&mut self, _bucket: Resource<Bucket>, _cursor: Option<u64>) -> Result<KeyResponse, keyvalue::Error> {
+        Ok(KeyResponse { keys: self.values.lock().unwrap().keys().cloned().collect(), cursor: None })
+    }
+
+    fn drop(&mut self, _bucket: Resource<Bucket>) -> wasmtime::Result<()> {
+        Ok(())
+    }
+}
+
+impl IoView for TestState {
+    fn table(&mut self) -> &mut ResourceTable { &mut self.table }
+}
+impl WasiView for TestState {
+    fn ctx(&mut self) -> &mut WasiCtx { &mut self.ctx }
+}
+
+impl WasiHttpView for TestState {
+    fn ctx(&mut self) -> &mut WasiHttpCtx {
+        &mut self.http
+    }
+
+    fn send_request(&mut self, mut request: hyper::Request<HyperOutgoingBody>, mut config: OutgoingRequestConfig) -> HttpResult<HostFutureIncomingResponse> {
+        let path = request.uri().path_and_query().map(|p| p.to_string()).unwrap_or_default();
+        match self.upstream {
+            Upstream::Responder(responder) => {
+                let response = responder(&path).map(|(status, body)| {
+                    let body = Full::new(Bytes::from(body)).map_err(|_| ErrorCode::InternalError(None)).boxed();
+                    IncomingResponse {
+                        resp: hyper::Response::builder().status(status).body(body).expect("invalid canned response"),
+                        worker: None,
+                        between_bytes_timeout: Duration::from_secs(5),
+                    }
+                });
+                Ok(HostFutureIncomingResponse::Ready(Ok(response)))
+            }
+            Upstream::Server(server) => {
+                *request.uri_mut() = format!("http://{}{}", server, path).parse().expect("invalid stand-in uri");
+                config.use_tls = false;
+                Ok(default_send_request(request, config))
+            }
+        }
+    }
+}
+
+/// The real clocks, moved ahead by the time a test skips to let cached responses expire.
+#[derive(Clone)]
+struct SkippingClock {
+    started: Instant,
+    skipped_ns: Arc<AtomicU64>,
+}
+
+impl HostMonotonicClock for SkippingClock {
+    fn resolution(&self) -> u64 { 1 }
+    fn now(&self) -> u64 {
+        self.started.elapsed().as_nanos() as u64 + self.skipped_ns.load(Ordering::SeqCst)
+    }
+}
+
+impl HostWallClock for SkippingClock {
+    fn resolution(&self) -> Duration { Duration::from_nanos(1) }
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_nanos(self.skipped_ns.load(Ordering::SeqCst))
+    }
+}
+
+/// Compiles the component once for all tests.
+pub fn component() -> &'static (Engine, Component) {
+    static COMPONENT: OnceLock<(Engine, Component)> = OnceLock::new();
+    COMPONENT.get_or_init(|| {
+        let file = "target/wasm32-wasip2/debug/mcp_weather_api.wasm";
+        let mut config = Config::default();
+        config.async_support(false);
+        let engine = Engine::new(&config).unwrap();
+        let component = Component::from_file(&engine, file).unwrap();
+        (engine, component)
+    })
+}
+
+/// Instantiates the component with the given secrets, sending its requests to `upstream`.
+pub fn instantiate(secrets: Vec<(&'static str, &'static str)>, upstream: Upstream, requests: Arc<AtomicUsize>) -> (Store<TestState>, McpSecretsKeyvalue) {
+    let (engine, component) = component();
+    let clock = SkippingClock { started: Instant::now(), skipped_ns: Arc::new(AtomicU64::new(0)) };
+    let state = TestState {
+        secrets,
+        upstream,
+        requests,
+        skipped_ns: clock.skipped_ns.clone(),
+        key_value_store: KeyValueStore::default(),
+        logs: Vec::new(),
+        table: ResourceTable::new(),
+        ctx: WasiCtxBuilder::new().monotonic_clock(clock.clone()).wall_clock(clock).build(),
+        http: WasiHttpCtx::new(),
+    };
+    let mut store = Store::new(engine, state);
+    let mut linker = Linker::new(engine);
+    wasmtime_wasi::add_to_linker_sync(&mut linker).expect("wasi linker not added");
+    wasmtime_wasi_http::add_only_http_to_linker_sync(&mut linker).expect("Could not add http to linker");
+    wasix::mcp::secrets_store::add_to_linker(&mut linker, |state: &mut TestState| state).expect("Could not link secrets store");
+    wasi::logging::logging::add_to_linker(&mut linker, |state: &mut TestState| state).expect("Could not link logging");
+    wasi::keyvalue::store::add_to_linker(&mut linker, |state: &mut TestState| &mut state.key_value_store).expect("Could not link keyvalue");
+    let router = McpSecretsKeyvalue::instantiate(&mut store, component, &linker).expect("Could not instantiate the component");
+    (store, router)
+}
+
+/// Calls a tool and fails the test when the component traps instead of returning a result.
+pub fn call(store: &mut Store<TestState>, router: &McpSecretsKeyvalue, tool_name: &str, arguments: impl Display) -> Result<CallToolResult, ToolError> {
+    let value = Value { json: arguments.to_string() };
+    match router.wasix_mcp_router().call_call_tool(&mut *store, tool_name, &value) {
+        Ok(result) => result,
+        Err(trap) => panic!("{} trapped on {}: {:?}", tool_name, arguments, trap),
+    }
+}
+
+/// Formats the UTC date `days` days after today (before today when negative) as yyyy-MM-dd.
+pub fn utc_date(days: i64) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let z = now / 86400 + days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Checks the json arguments of a tool call against the tool's advertised input schema.
+pub fn arguments(tools: &[Tool], tool_name: &str, arguments: serde_json::Value) -> Value {
+    let tool = tools.iter().find(|tool| tool.name == tool_name).expect("tool not listed");
+    let schema: serde_json::Value = serde_json::from_str(&tool.input_schema.json).expect("input schema is not json");
+    if let Err(error) = check_schema(&arguments, &schema, &schema, "$") {
+        panic!("{} arguments do not match its input schema: {}", tool_name, error);
+    }
+    Value { json: arguments.to_string() }
+}
+
+/// Parses the json result of a tool call and asserts it matches the tool's advertised output schema.
+pub fn structured_content(tools: &[Tool], tool_name: &str, result: &CallToolResult) -> serde_json::Value {
+    let tool = tools.iter().find(|tool| tool.name == tool_name).expect("tool not listed");
+    let schema: serde_json::Value = serde_json::from_str(&tool.output_schema.as_ref().expect("tool has no output schema").json)
+        .expect("output schema is not json");
+    let value = match &result.content[0] {
+        Content::Text(text_content) => serde_json::from_str::<serde_json::Value>(&text_content.text).expect("result is not json"),
+        _ => panic!("Not right content"),
+    };
+    if let Err(error) = check_schema(&value, &schema, &schema, "$") {
+        panic!("{} result does not match its output schema: {}", tool_name, error);
+    }
+    value
+}
+
+/// Checks `value` against the subset of JSON Schema the tools use, rejecting undeclared properties.
+fn check_schema(value: &serde_json::Value, schema: &serde_json::Value, root: &serde_json::Value, path: &str) -> Result<(), String> {
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.trim_start_matches("#/$defs/");
+        return check_schema(value, &root["$defs"][name], root, path);
+    }
+    if let Some(options) = schema["anyOf"].as_array() {
+        return options.iter()
+            .map(|option| check_schema(value, option, root, path))
+            .find(Result::is_ok)
+            .unwrap_or_else(|| Err(format!("{} matches none of the anyOf schemas", path)));
+    }
+    let types: Vec<&str> = match &schema["type"] {
+        serde_json::Value::String(t) => vec![t.as_str()],
+        serde_json::Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).collect(),
+        _ => Vec::new(),
+    };
+    let type_matches = |t: &str| match t {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => false,
+    };
+    if !types.is_empty() && !types.iter().any(|t| type_matches(t)) {
+        return Err(format!("{} is {} but should be {:?}", path, value, types));
+    }
+    if let (Some(object), Some(properties)) = (value.as_object(), schema["properties"].as_object()) {
+        for (key, property) in object {
+            let property_schema = properties.get(key).ok_or_else(|| format!("{}.{} is not declared", path, key))?;
+            check_schema(property, property_schema, root, &format!("{}.{}", path, key))?;
+        }
+        for required in schema["required"].as_array().into_iter().flatten().filter_map(|r| r.as_str()) {
+            if !object.contains_key(required) {
+                return Err(format!("{}.{} is required", path, required));
+            }
+        }
+    }
+    if let (Some(items), Some(item_schema)) = (value.as_array(), schema.get("items")) {
+        for (i, item) in items.iter().enumerate() {
+            check_schema(item, item_schema, root, &format!("{}[{}]", path, i))?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+//! A local stand-in for the weather api server, answering every request with a scripted response.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use serde_json::json;
+use wasmtime::Store;
+use super::exports::wasix::mcp::router::{CallToolResult, Content};
+use super::{McpSecretsKeyvalue, TestState, Upstream};
+
+/// How the stand-in server sends every response body.
+pub struct Script {
+    pub body: Vec<u8>,
+    /// The sizes of the chunks, repeated until the body is sent.
+    pub chunk_sizes: &'static [usize],
+    /// Raw trailer lines, each ending in \r\n.
+    pub trailers: &'static str,
+    /// Whether to end the body properly, or hang up halfway through it.
+    pub complete: bool,
+    /// Where the server goes quiet for a few seconds.
+    pub stall: Stall,
+    /// The status line with headers and the body the first requests are answered with instead.
+    pub failures: &'static [(&'static str, &'static str)],
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stall {
+    Never,
+    BeforeHead,
+    MidBody,
+}
+
+impl Default for Script {
+    /// A complete 14 day forecast in 4096 byte chunks, sent at once.
+    fn default() -> Script {
+        Script { body: large_forecast(), chunk_sizes: &[4096], trailers: "", complete: true, stall: Stall::Never, failures: &[] }
+    }
+}
+
+pub const STALL: Duration = Duration::from_secs(3);
+
+/// Starts a local server that answers every request with the scripted chunked response.
+pub fn serve(script: Script) -> (SocketAddr, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind the stand-in server");
+    let address = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let request = counter.fetch_add(1, Ordering::SeqCst);
+            respond(stream, &script, request).ok();
+        }
+    });
+    (address, requests)
+}
+
+fn respond(mut stream: TcpStream, script: &Script, request: usize) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buffer[..read]);
+    }
+    if script.stall == Stall::BeforeHead {
+        thread::sleep(STALL);
+    }
+    if let Some((status, body)) = script.failures.get(request) {
+        write!(stream, "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)?;
+        return stream.flush();
+    }
+    stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n")?;
+
+    let body = if script.complete { &script.body[..] } else { &script.body[..script.body.len() / 2] };
+    let mut rest = body;
+    for size in script.chunk_sizes.iter().cycle() {
+        if rest.is_empty() {
+            break;
+        }
+        let (chunk, tail) = rest.split_at((*size).min(rest.len()));
+        write!(stream, "{:x}\r\n", chunk.len())?;
+        stream.write_all(chunk)?;
+        stream.write_all(b"\r\n")?;
+        stream.flush()?;
+        // Pause so the component sees the chunks as separate, short reads
+        thread::sleep(Duration::from_millis(1));
+        if script.stall == Stall::MidBody && tail.len() < body.len() / 2 && rest.len() >= body.len() / 2 {
+            thread::sleep(STALL);
+        }
+        rest = tail;
+    }
+    if script.complete {
+        write!(stream, "0\r\n{}\r\n", script.trailers)?;
+    }
+    stream.flush()
+}
+
+/// A 14 day forecast with every hour filled in, well over a hundred 1024 byte reads.
+pub fn large_forecast() -> Vec<u8> {
+    let location = json!({ "name": "London", "region": "City of London", "country": "United Kingdom", "lat": 51.52, "lon": -0.11 });
+    let forecastday: Vec<serde_json::Value> = (1..=14).map(|day| json!({
+        "date": format!("2025-10-{:02}", day),
+        "day": { "maxtemp_c": 15.2, "mintemp_c": 8.1, "condition": { "text": "Patchy rain nearby", "code": 1063 } },
+        "astro": { "sunrise": "07:12 AM", "sunset": "06:21 PM" },
+        "hour": (0..24).map(|hour| json!({
+            "time": format!("2025-10-{:02} {:02}:00", day, hour),
+            "temp_c": 11.4, "wind_kph": 14.8, "wind_dir": "WSW", "humidity": 81, "chance_of_rain": 64,
+            "condition": { "text": "Patchy rain nearby", "icon": "//cdn.weatherapi.com/weather/64x64/day/176.png", "code": 1063 },
+        })).collect::<Vec<_>>(),
+    })).collect();
+    json!({ "location": location, "current": {}, "forecast": { "forecastday": forecastday } }).to_string().into_bytes()
+}
+
+/// Instantiates the component with the given secrets against a stand-in server running `script`.
+pub fn instantiate(secrets: Vec<(&'static str, &'static str)>, script: Script) -> (Store<TestState>, McpSecretsKeyvalue) {
+    let (server, requests) = serve(script);
+    super::instantiate(secrets, Upstream::Server(server), requests)
+}
+
+pub fn json_content(result: &CallToolResult) -> serde_json::Value {
+    match &result.content[0] {
+        Content::Text(text_content) => serde_json::from_str(&text_content.text).expect("result is not json"),
+        _ => panic!("Not right content"),
+    }
+}
@@ -0,0 +1,181 @@
+mod common;
+
+use std::sync::Arc;
+use common::exports::wasix::mcp::router::ToolError;
+use common::{arguments, call, structured_content, utc_date, Responder, TestState, Upstream};
+use serde_json::json;
+use wasmtime::Store;
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+
+/// Instantiates the component with the given api key secret and weather api stand-in.
+fn instantiate(weather_api_key: Option<&'static str>, responder: Responder) -> (Store<TestState>, common::McpSecretsKeyvalue) {
+    let secrets = weather_api_key.map(|key| vec![("WEATHER_API_KEY", key)]).unwrap_or_default();
+    common::instantiate(secrets, Upstream::Responder(responder), Arc::default())
+}
+
+/// A minimal but well formed weatherapi.com response for every endpoint.
+fn well_formed(path: &str) -> Result<(u16, Vec<u8>), ErrorCode> {
+    let location = json!({ "name": "London", "region": "City of London", "country": "United Kingdom", "lat": 51.52, "lon": -0.11 });
+    let day = json!({ "date": "2025-10-09", "day": {}, "astro": {}, "hour": [] });
+    let body = match path.split('?').next().unwrap_or_default() {
+        "/v1/search.json" => json!([{ "id": 2801268, "name": "London", "region": "City of London", "country": "United Kingdom", "lat": 51.52, "lon": -0.11 }]),
+        "/v1/ip.json" => json!({ "ip": "8.8.8.8" }),
+        "/v1/sports.json" => json!({ "football": [], "cricket": [], "golf": [] }),
+        "/v1/astronomy.json" => json!({ "location": location, "astronomy": { "astro": {} } }),
+        "/v1/alerts.json" => json!({ "location": location, "alerts": { "alert": [] } }),
+        "/v1/marine.json" => json!({ "location": location, "forecast": { "forecastday": [{ "date": "2025-10-09", "day": {}, "hour": [] }] } }),
+        "/v1/forecast.json" | "/v1/history.json" | "/v1/future.json" => json!({ "location": location, "current": {}, "forecast": { "forecastday": [day] } }),
+        _ => json!({ "location": location, "current": {} }),
+    };
+    Ok((200, body.to_string().into_bytes()))
+}
+
+#[test]
+fn test_hostile_arguments_never_trap() {
+    let (mut store, router) = instantiate(Some("key"), well_formed);
+    let tools = router.wasix_mcp_router().call_list_tools(&mut store).unwrap();
+    let hostile = [
+        "", "nope", "null", "42", "\"London\"", "[]", "{", "{}",
+        r#"{"location": null}"#, r#"{"location": 42}"#, r#"{"location": ["London"]}"#, r#"{"location": {}}"#,
+        r#"{"location": ""}"#, r#"{"location": "   "}"#, r#"{"location": "\u0000￿"}"#, r#"{"location": ",,,"}"#,
+        r#"{"location": "London", "disambiguate": "yes"}"#, r#"{"location": "London", "disambiguate": true}"#,
+        r#"{"location": "London", "days": -1}"#, r#"{"location": "London", "days": 1.5}"#, r#"{"location": "London", "days": 1e300}"#,
+        r#"{"location": "London", "days": 18446744073709551615}"#, r#"{"location": "London", "hour": 99}"#,
+        r#"{"location": "London", "dt": "2025-02-30"}"#, r#"{"location": "London", "dt": "-1-01-01"}"#,
+        r#"{"location": "London", "dt": "99999999-01-01"}"#, r#"{"location": "London", "dt": 20250101}"#,
+        r#"{"location": "London", "dt": "2025-10-09", "end_dt": "1900-01-01"}"#,
+        r#"{"query": null}"#, r#"{"query": ""}"#, r#"{"ip": false}"#, r#"{"ip": "not an ip"}"#,
+    ];
+    for tool in tools.iter().map(|tool| tool.name.as_str()).chain(["no_such_tool"]) {
+        for arguments in hostile {
+            call(&mut store, &router, tool, arguments).ok();
+        }
+    }
+
+    // A blank location never reaches the api either
+    for tool in tools.iter().filter(|tool| tool.input_schema.json.contains("\"location\"")) {
+        for blank in ["", " \t "] {
+            let arguments = match tool.name.as_str() {
+                "get_history" => json!({ "location": blank, "dt": utc_date(-3) }),
+                "get_future_weather" => json!({ "location": blank, "dt": utc_date(30) }),
+                _ => json!({ "location": blank }),
+            };
+            match call(&mut store, &router, &tool.name, &arguments) {
+                Err(ToolError::InvalidParameters(message)) => assert!(message.contains("location"), "unexpected message: {}", message),
+                other => panic!("{} with {} gave {:?}", tool.name, arguments, other),
+            }
+        }
+    }
+
+    // Arguments that do not match the input schema never reach the api
+    for arguments in ["nope", "[]", "{}", r#"{"location": 42}"#, r#"{"location": "London", "days": 1.5}"#] {
+        let result = call(&mut store, &router, "get_forecast", arguments);
+        assert!(matches!(result, Err(ToolError::InvalidParameters(_))), "{} gave {:?}", arguments, result);
+    }
+}
+
+#[test]
+fn test_results_match_their_output_schemas() {
+    let (mut store, router) = instantiate(Some("key"), well_formed);
+    let tools = router.wasix_mcp_router().call_list_tools(&mut store).unwrap();
+    for tool in &tools {
+        let json = match tool.name.as_str() {
+            "get_forecast" => json!({ "location": "London", "days": 2 }),
+            "get_history" => json!({ "location": "London", "dt": utc_date(-3) }),
+            "get_future_weather" => json!({ "location": "London", "dt": utc_date(30) }),
+            "search_locations" => json!({ "query": "London" }),
+            "lookup_ip" => json!({ "ip": "8.8.8.8" }),
+            _ => json!({ "location": "London" }),
+        };
+        let value = arguments(&tools, &tool.name, json);
+        let result = call(&mut store, &router, &tool.name, &value.json).unwrap_or_else(|e| panic!("{} failed: {:?}", tool.name, e));
+        structured_content(&tools, &tool.name, &result);
+    }
+}
+
+#[test]
+fn test_missing_secret_is_an_execution_error() {
+    let (mut store, router) = instantiate(None, well_formed);
+    match call(&mut store, &router, "get_weather", r#"{"location": "London"}"#) {
+        Err(ToolError::ExecutionError(message)) => assert!(message.contains("WEATHER_API_KEY"), "unexpected message: {}", message),
+        other => panic!("expected an ExecutionError, got {:?}", other),
+    }
+
+    let (mut store, router) = instantiate(Some(""), well_formed);
+    match call(&mut store, &router, "get_weather", r#"{"location": "London"}"#) {
+        Err(ToolError::ExecutionError(message)) => assert!(message.contains("WEATHER_API_KEY"), "unexpected message: {}", message),
+        other => panic!("expected an ExecutionError, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_transport_failure_is_an_execution_error() {
+    let (mut store, router) = instantiate(Some("key"), |_| Err(ErrorCode::ConnectionRefused));
+    let result = call(&mut store, &router, "get_weather", r#"{"location": "London"}"#);
+    assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+
+    let (mut store, router) = instantiate(Some("key"), |_| Ok((503, b"Service Unavailable".to_vec())));
+    let result = call(&mut store, &router, "get_weather", r#"{"location": "London"}"#);
+    assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+}
+
+#[test]
+fn test_api_errors_are_actionable() {
+    let (mut store, router) = instantiate(Some("key"), |_| Ok((400, br#"{"error":{"code":1006,"message":"No matching location found."}}"#.to_vec())));
+    match call(&mut store, &router, "get_forecast", r#"{"location": "Nowhereville"}"#) {
+        Err(ToolError::InvalidParameters(message)) => {
+            assert!(message.contains("Nowhereville") && message.contains("search_locations"), "unexpected message: {}", message);
+        }
+        other => panic!("expected InvalidParameters, got {:?}", other),
+    }
+
+    let failures: [(Responder, &str); 4] = [
+        (|_| Ok((401, br#"{"error":{"code":2006,"message":"API key is invalid."}}"#.to_vec())), "not a valid"),
+        (|_| Ok((403, br#"{"error":{"code":2008,"message":"API key has been disabled."}}"#.to_vec())), "disabled"),
+        (|_| Ok((403, br#"{"error":{"code":2007,"message":"API key has exceeded calls per month quota."}}"#.to_vec())), "quota"),
+        (|_| Ok((400, br#"{"error":{"code":9999,"message":"Internal application error."}}"#.to_vec())), "Internal application error"),
+    ];
+    for (responder, expected) in failures {
+        let (mut store, router) = instantiate(Some("key"), responder);
+        match call(&mut store, &router, "get_weather", r#"{"location": "London"}"#) {
+            Err(ToolError::ExecutionError(message)) => assert!(message.contains(expected), "unexpected message: {}", message),
+            other => panic!("expected an ExecutionError, got {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn test_astronomy_always_asks_for_a_date() {
+    // weatherapi.com requires dt for astronomy.json
+    let (mut store, router) = instantiate(Some("key"), |path| match path.starts_with("/v1/astronomy.json") && !path.contains("&dt=") {
+        true => Ok((400, br#"{"error":{"code":1003,"message":"Parameter dt is missing."}}"#.to_vec())),
+        false => well_formed(path),
+    });
+    call(&mut store, &router, "get_astronomy", r#"{"location": "London"}"#).expect("get_astronomy failed without a dt");
+    call(&mut store, &router, "get_astronomy", r#"{"location": "London", "dt": "2025-10-09"}"#).expect("get_astronomy failed with a dt");
+}
+
+#[test]
+fn test_bad_bodies_are_schema_errors() {
+    let bodies: [Responder; 6] = [
+        |_| Ok((200, Vec::new())),
+        |_| Ok((200, vec![0xff, 0xfe, 0x7b, 0x7d])),
+        |_| Ok((200, b"{\"location\": {\"name\": \"Lon".to_vec())),
+        |_| Ok((200, b"{}".to_vec())),
+        |_| Ok((200, b"{\"location\": 5, \"current\": {}}".to_vec())),
+        |_| Ok((200, b"<html>Bad Gateway</html>".to_vec())),
+    ];
+    for responder in bodies {
+        let (mut store, router) = instantiate(Some("key"), responder);
+        for (tool, arguments) in [
+            ("get_weather", json!({ "location": "London" })),
+            ("get_forecast", json!({ "location": "London", "days": 2 })),
+            ("get_history", json!({ "location": "London", "dt": "2025-10-01", "end_dt": "2025-10-02" })),
+            ("search_locations", json!({ "query": "London" })),
+            ("get_weather", json!({ "location": "Springfield", "disambiguate": true })),
+        ] {
+            let result = call(&mut store, &router, tool, &arguments);
+            assert!(matches!(result, Err(ToolError::SchemaError(_))), "{} gave {:?}", tool, result);
+        }
+    }
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use std::net::TcpListener;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+use common::exports::wasix::mcp::router::ToolError;
+use common::server::{instantiate, Script};
+use common::{call, TestState, Upstream};
+use serde_json::json;
+use wasmtime::Store;
+
+#[test]
+fn test_transient_failures_are_retried() {
+    let failures = &[("503 Service Unavailable", ""), ("502 Bad Gateway", "<html>Bad Gateway</html>")];
+    let script = Script { failures, ..Script::default() };
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })).expect("the retries did not succeed");
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 3);
+
+    // Retry-After is waited for, in seconds or as an HTTP date
+    let failures = &[("429 Too Many Requests\r\nRetry-After: 1", ""), ("503 Service Unavailable\r\nRetry-After: Wed, 21 Oct 2015 07:28:00 GMT", "")];
+    let script = Script { failures, ..Script::default() };
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    let started = Instant::now();
+    call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })).expect("the retries did not succeed");
+    assert!(started.elapsed() >= Duration::from_secs(1), "Retry-After was not honoured");
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 3);
+
+    // A connection that is refused is retried as well
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")],
+        Script::default());
+    let refused = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    store.data_mut().upstream = Upstream::Server(refused);
+    let started = Instant::now();
+    let result = call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 }));
+    assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+    assert!(started.elapsed() >= Duration::from_millis(750), "the refused connection was not retried with backoff");
+}
+
+#[test]
+fn test_persistent_and_client_failures_are_not_retried_forever() {
+    let failures = &[("500 Internal Server Error", ""); 5];
+    let script = Script { failures, ..Script::default() };
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    let result = call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 }));
+    assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 3);
+
+    // A Retry-After beyond the call budget is reported instead of waited for
+    let failures = &[("429 Too Many Requests\r\nRetry-After: 3600", "")];
+    let script = Script { failures, ..Script::default() };
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    match call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })) {
+        Err(ToolError::ExecutionError(message)) => assert!(message.contains("3600 seconds"), "unexpected message: {}", message),
+        other => panic!("expected an ExecutionError, got {:?}", other),
+    }
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 1);
+
+    // So is a Retry-After date millions of years away, on a failure or on any other response
+    let failures = &[("503 Service Unavailable\r\nRetry-After: Wed, 21 Oct 2147483647 07:28:00 GMT", "")];
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], Script { failures, ..Script::default() });
+    match call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })) {
+        Err(ToolError::ExecutionError(message)) => assert!(message.contains("asked to retry after"), "unexpected message: {}", message),
+        other => panic!("expected an ExecutionError, got {:?}", other),
+    }
+    let failures = &[("200 OK\r\nContent-Type: application/json\r\nRetry-After: Wed, 21 Oct 2147483647 07:28:00 GMT",
+        r#"{"location":{"name":"London","region":"City of London","country":"United Kingdom","lat":51.52,"lon":-0.11},"current":{},"forecast":{"forecastday":[]}}"#)];
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], Script { failures, ..Script::default() });
+    call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })).expect("get_forecast failed");
+
+    // 4xx errors such as an unknown location or an invalid key never are
+    for (failure, invalid_parameters) in [
+        (("400 Bad Request", r#"{"error":{"code":1006,"message":"No matching location found."}}"#), true),
+        (("401 Unauthorized", r#"{"error":{"code":2006,"message":"API key is invalid."}}"#), false),
+        (("403 Forbidden", r#"{"error":{"code":2007,"message":"API key has exceeded calls per month quota."}}"#), false),
+    ] {
+        let failures: &'static [(&'static str, &'static str)] = Box::leak(vec![failure; 5].into_boxed_slice());
+        let script = Script { failures, ..Script::default() };
+        let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+        let result = call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 }));
+        assert_eq!(matches!(result, Err(ToolError::InvalidParameters(_))), invalid_parameters, "got {:?}", result);
+        assert_eq!(store.data().requests.load(Ordering::SeqCst), 1);
+    }
+}
+
+#[test]
+fn test_circuit_breaker_fails_fast_and_probes() {
+    let secrets = vec![("WEATHER_API_KEY", "key"), ("WEATHER_API_FAILURE_THRESHOLD", "2"), ("WEATHER_API_COOL_DOWN_MS", "1000")];
+    let script = Script::default();
+    let (mut store, router) = instantiate(secrets, script);
+    let upstream = store.data().upstream;
+    let diagnostics = |store: &mut Store<TestState>| -> serde_json::Value {
+        let result = router.wasix_mcp_router().call_read_resource(&mut *store, "weather-api://diagnostics").unwrap().expect("no diagnostics");
+        match &result.contents[0] {
+            common::exports::wasix::mcp::router::ResourceContents::Text(text) => serde_json::from_str(&text.text).expect("diagnostics are not json"),
+            _ => panic!("Not right content"),
+        }
+    };
+    let arguments = json!({ "location": "London", "days": 14 });
+
+    // Clients only look for the diagnostics resource when resources are advertised
+    let capabilities = router.wasix_mcp_router().call_capabilities(&mut store).unwrap();
+    assert!(capabilities.resources.is_some(), "resources are not advertised");
+    let resources = router.wasix_mcp_router().call_list_resources(&mut store).unwrap();
+    assert!(resources.iter().any(|resource| resource.uri == "weather-api://diagnostics"));
+
+    // The circuit opens after two failed calls...
+    store.data_mut().upstream = Upstream::Server(TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap());
+    for _ in 0..2 {
+        let result = call(&mut store, &router, "get_forecast", arguments.clone());
+        assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+    }
+    assert_eq!(diagnostics(&mut store)["state"], "open");
+
+    // ...and then fails fast
+    let started = Instant::now();
+    match call(&mut store, &router, "get_forecast", arguments.clone()) {
+        Err(ToolError::ExecutionError(message)) => assert!(message.starts_with("weather provider unavailable, retry after"), "unexpected message: {}", message),
+        other => panic!("expected an ExecutionError, got {:?}", other),
+    }
+    assert!(started.elapsed() < Duration::from_millis(250), "the open circuit did not fail fast");
+    let status = diagnostics(&mut store);
+    assert_eq!(status["consecutive_failures"], 2);
+    assert_eq!(status["rejected"], 1);
+    assert_eq!(status["retry_after_seconds"], 1);
+
+    // After the cool-down a single probe goes through, without retries, and reopens the circuit when it fails
+    thread::sleep(Duration::from_millis(1100));
+    let started = Instant::now();
+    let result = call(&mut store, &router, "get_forecast", arguments.clone());
+    assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+    assert!(started.elapsed() < Duration::from_millis(250), "the probe was retried");
+    assert_eq!(diagnostics(&mut store)["state"], "open");
+
+    // A probe that succeeds closes it again
+    thread::sleep(Duration::from_millis(1100));
+    store.data_mut().upstream = upstream;
+    call(&mut store, &router, "get_forecast", arguments.clone()).expect("the probe failed");
+    let status = diagnostics(&mut store);
+    assert_eq!(status["state"], "closed");
+    assert_eq!(status["consecutive_failures"], 0);
+    call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 13 })).expect("the closed circuit failed");
+    assert_eq!(store.data().requests.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use common::exports::wasix::mcp::router::ToolError;
+use common::server::{instantiate, json_content, large_forecast, Script, Stall, STALL};
+use common::call;
+use serde_json::json;
+
+#[test]
+fn test_chunked_body_is_read_completely() {
+    let script = Script { chunk_sizes: &[1, 1023, 7, 1024, 1025, 300, 4096], ..Script::default() };
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    let result = call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })).expect("get_forecast failed");
+    let forecast = json_content(&result);
+    let days = forecast["forecastday"].as_array().expect("forecastday is not an array");
+    assert_eq!(days.len(), 14);
+    assert!(days.iter().all(|day| day["hour"].as_array().map(Vec::len) == Some(24)));
+}
+
+#[test]
+fn test_trailers_are_accepted() {
+    let script = Script { chunk_sizes: &[512, 4000], trailers: "X-Checksum: 6f1ed002ab\r\nServer-Timing: db;dur=53\r\n", ..Script::default() };
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    let result = call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })).expect("get_forecast failed");
+    assert_eq!(json_content(&result)["forecastday"].as_array().map(Vec::len), Some(14));
+}
+
+#[test]
+fn test_truncated_body_is_an_execution_error() {
+    let script = Script { chunk_sizes: &[1000], complete: false, ..Script::default() };
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key")], script);
+    let result = call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 }));
+    assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+}
+
+#[test]
+fn test_body_size_is_limited() {
+    let body = large_forecast();
+    let exact: &'static str = Box::leak(body.len().to_string().into_boxed_str());
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key"), ("WEATHER_API_MAX_BODY_BYTES", exact)],
+        Script { body: body.clone(), chunk_sizes: &[8192], ..Script::default() });
+    call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })).expect("a body of exactly the limit failed");
+
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key"), ("WEATHER_API_MAX_BODY_BYTES", "4096")],
+        Script { body: body.clone(), chunk_sizes: &[8192], ..Script::default() });
+    match call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })) {
+        Err(ToolError::ExecutionError(message)) => assert!(message.contains("WEATHER_API_MAX_BODY_BYTES"), "unexpected message: {}", message),
+        other => panic!("expected an ExecutionError, got {:?}", other),
+    }
+
+    let (mut store, router) = instantiate(vec![("WEATHER_API_KEY", "key"), ("WEATHER_API_MAX_BODY_BYTES", "lots")],
+        Script { body, chunk_sizes: &[8192], ..Script::default() });
+    let result = call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 }));
+    assert!(matches!(result, Err(ToolError::ExecutionError(_))), "got {:?}", result);
+}
+
+#[test]
+fn test_stalled_responses_time_out() {
+    let secrets = vec![("WEATHER_API_KEY", "key"), ("WEATHER_API_FIRST_BYTE_TIMEOUT_MS", "500"), ("WEATHER_API_BETWEEN_BYTES_TIMEOUT_MS", "500")];
+    for (stall, timeout) in [(Stall::BeforeHead, "WEATHER_API_FIRST_BYTE_TIMEOUT_MS"), (Stall::MidBody, "WEATHER_API_BETWEEN_BYTES_TIMEOUT_MS")] {
+        let script = Script { stall, ..Script::default() };
+        let (mut store, router) = instantiate(secrets.clone(), script);
+        let started = std::time::Instant::now();
+        match call(&mut store, &router, "get_forecast", json!({ "location": "London", "days": 14 })) {
+            Err(ToolError::ExecutionError(message)) => {
+                assert!(message.contains("timed out") && message.contains(timeout), "unexpected message: {}", message);
+            }
+            other => panic!("expected an ExecutionError, got {:?}", other),
+        }
+        assert!(started.elapsed() < STALL, "the call waited for the stalled server");
+    }
+}
@@ -1,24 +1,26 @@
-
+// The baseline test code is kept as it was written
+#![allow(dead_code, clippy::nonminimal_bool, clippy::single_component_path_imports, clippy::unnecessary_literal_unwrap, clippy::unused_unit)]
 
 use std::env;
 use dotenvy::dotenv;
-use exports::wasix::mcp::router::{Content, PromptMessageContent, Role, Value};
+use exports::wasix::mcp::router::{Content, PromptMessageContent, ResourceContents, Role, ToolError, Value};
 use serde_json::json;
 use tracing;
 use wasix::mcp;
 use wasix::mcp::secrets_store::{HostSecret, Secret, SecretValue, SecretsError};
 use wasmtime_wasi::{IoView, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
 use wasmtime::{Config, Engine, Store};
-use wasmtime::component::{bindgen, Component, Linker, Resource};
+use wasmtime::component::{Component, Linker, Resource};
 use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 use crate::wasi::logging::logging;
 use crate::logging::Level;
-const INSTRUCTIONS: &str = "Fetches the current weather \n        for a given location. \n        Call the get_weather tool and pass a json {'location'='input your location here'}, \n        as input. Location can be in different formats:\n        * Latitude and Longitude (Decimal degree) e.g: location=48.8567,2.3508\n        * city name e.g.: location=Paris\n        * US zip e.g.: location=10001\n        * UK postcode e.g: location=SW1\n        * Canada postal code e.g: location=G2J\n        * metar:<metar code> e.g: location=metar:EGLL\n        * iata:<3 digit airport code> e.g: location=iata:DXB\n        * auto:ip IP lookup e.g: location=auto:ip\n        * IP address (IPv4 and IPv6 supported) e.g: location=100.0.0.1\n        * By ID returned from Search API. e.g: location=id:2801268";
+const INSTRUCTIONS: &str = "Fetches weather data from weatherapi.com \n        for a given location. \n        Call the get_weather tool for the current weather, get_forecast for the coming days,\n        get_future_weather for 14 to 300 days ahead, get_history for past days, get_marine for the sea,\n        get_astronomy for the sun and moon, get_alerts for weather alerts, get_air_quality for pollutants\n        and pollen, get_sports_events for upcoming events, get_timezone for the local time and\n        search_locations to find a place or lookup_ip to locate an IP address.\n        Pass a json such as {'location'='input your location here'}, \n        as input. Location can be in different formats:\n        * Latitude and Longitude (Decimal degree) e.g: location=48.8567,2.3508\n        * city name e.g.: location=Paris\n        * US zip e.g.: location=10001\n        * UK postcode e.g: location=SW1\n        * Canada postal code e.g: location=G2J\n        * metar:<metar code> e.g: location=metar:EGLL\n        * iata:<3 digit airport code> e.g: location=iata:DXB\n        * auto:ip IP lookup e.g: location=auto:ip\n        * IP address (IPv4 and IPv6 supported) e.g: location=100.0.0.1\n        * By ID returned from the search_locations tool. e.g: location=id:2801268";
+
 
+mod common;
 
-bindgen!({
-    world: "mcp-secrets",
-});
+// The bindings of the keyvalue world, which also runs the component built without the keyvalue feature
+use common::{arguments, exports, structured_content, utc_date, wasi, wasix, KeyValueStore, McpSecretsKeyvalue};
 
 #[derive(Debug, Clone, Copy)]
 struct SecretsStore {
@@ -31,6 +33,7 @@ struct Logging;
 struct MyState {
     secrets_store: SecretsStore,
     logging: Logging,
+    key_value_store: KeyValueStore,
     table: ResourceTable,
     ctx: WasiCtx,
     http: WasiHttpCtx,
@@ -51,8 +54,11 @@ impl WasiHttpView for MyState {
 
 impl mcp::secrets_store::Host for MyState{
     #[doc = " Gets a single opaque secrets value set at the given key if it exists"]
-    fn get(&mut self,_key:wasmtime::component::__internal::String,) -> Result<Resource<Secret>,SecretsError> {
-            Ok(Resource::<Secret>::new_own(1))
+    fn get(&mut self,key:wasmtime::component::__internal::String,) -> Result<Resource<Secret>,SecretsError> {
+        match key.as_str() {
+            "WEATHER_API_KEY" => Ok(Resource::<Secret>::new_own(1)),
+            _ => Err(SecretsError::NotFound),
+        }
     }
 
     fn reveal(&mut self,_s:wasmtime::component::Resource<Secret>,) -> SecretValue {
@@ -99,6 +105,7 @@ fn test_weather_api_router() {
     let state = MyState {
         secrets_store,
         logging,
+        key_value_store: KeyValueStore::default(),
         ctx: wasi,
         http: WasiHttpCtx::new(),
         table: ResourceTable::new(),
@@ -110,8 +117,9 @@ fn test_weather_api_router() {
     wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).expect("Could not add http to linker");
     mcp::secrets_store::add_to_linker(&mut linker,  |state: &mut MyState| state).expect("Could not link secrets store");
     wasi::logging::logging::add_to_linker(&mut linker, |state: &mut MyState| state).expect("Could not link logging");
+    wasi::keyvalue::store::add_to_linker(&mut linker, |state: &mut MyState| &mut state.key_value_store).expect("Could not link keyvalue");
 
-    let router = McpSecrets::instantiate(&mut store, &component, &linker);//.unwrap();
+    let router = McpSecretsKeyvalue::instantiate(&mut store, &component, &linker);//.unwrap();
     let router = match router {
         Ok(mcp) => mcp,
         Err(err) =>  {eprint!("Error: {:?}",err); Err(err).expect("error")}
@@ -123,23 +131,18 @@ fn test_weather_api_router() {
     let instructions = mcp.call_instructions(&mut store).unwrap();
     assert_eq!(instructions, INSTRUCTIONS.to_string());
     let tools = mcp.call_list_tools(&mut store).unwrap();
-    assert_eq!(tools.len(), 1);  // Assuming only 1 tool is added in the implementation
+    assert_eq!(tools.len(), 12);
     assert_eq!(tools[0].name, "get_weather");
-    let left: serde_json::Value = serde_json::from_str(&tools[0].input_schema.json)
-    .expect("failed to parse left JSON");
-    let right: serde_json::Value = serde_json::from_str(r#"{
-        "type": "object",
-        "properties": {
-            "location": {
-                "type": "string"
-            }
-        },
-        "required": [
-            "location"
-        ]
-    }"#)
-        .expect("failed to parse right JSON");
-    assert_eq!(left, right);
+    // The schemas are generated from the argument and result types of the tools
+    let input_schema: serde_json::Value = serde_json::from_str(&tools[0].input_schema.json)
+        .expect("input schema is not json");
+    assert_eq!(input_schema["type"], "object");
+    assert_eq!(input_schema["required"], json!(["location"]));
+    assert_eq!(input_schema["properties"]["location"]["type"], "string");
+    assert_eq!(input_schema["properties"]["disambiguate"]["type"], json!(["boolean", "null"]));
+    let output_schema: serde_json::Value = serde_json::from_str(&tools[0].output_schema.as_ref().expect("get_weather has no output schema").json)
+        .expect("output schema is not json");
+    assert_eq!(output_schema["$defs"]["Current"]["properties"]["uv"]["type"], json!(["number", "null"]));
 
     // Test the 'call-tool' function
     let location_json = json!({
@@ -162,19 +165,225 @@ fn test_weather_api_router() {
         _ => Err("Not right content")
     };
     assert!(!result.is_err());
+    let weather = structured_content(&tools, "get_weather", &call_tool_result);
+    assert!(weather["current"]["temp_c"].is_number());
+    assert!(weather.get("forecastday").is_none());
+
+    // A location weatherapi.com does not know points the agent at search_locations
+    let value = arguments(&tools, "get_weather", json!({ "location": "Qxzvwkjhgfd" }));
+    match mcp.call_call_tool(&mut store, "get_weather", &value).expect("expected a CallToolResult") {
+        Err(ToolError::InvalidParameters(message)) => assert!(message.contains("search_locations"), "unexpected message: {}", message),
+        other => panic!("expected InvalidParameters, got {:?}", other),
+    }
+
+    // Test the 'get_forecast' tool
+    assert_eq!(tools[1].name, "get_forecast");
+    let value = arguments(&tools, "get_forecast", json!({ "location": "London", "days": 3, "alerts": true }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_forecast", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let forecast = structured_content(&tools, "get_forecast", &call_tool_result);
+    assert_eq!(forecast["location"]["name"], "London");
+    assert_eq!(forecast["forecastday"].as_array().expect("forecastday is not an array").len(), 3);
+
+    let value = Value {
+        json: json!({ "location": "London", "days": 15 }).to_string(),
+    };
+    let tool_result = mcp.call_call_tool(&mut store, "get_forecast", &value).expect("expected a CallToolResult");
+    assert!(matches!(tool_result, Err(ToolError::InvalidParameters(_))));
+
+    // Arguments that do not match the input schema name the field and its expected type
+    for (arguments, field, expected) in [
+        (json!({ "days": 3 }).to_string(), "location", "a string"),
+        (json!({ "location": 42 }).to_string(), "location", "a string"),
+        (json!({ "location": "London", "days": "3" }).to_string(), "days", "an integer"),
+        (json!({ "location": "London", "aqi": "yes" }).to_string(), "aqi", "a boolean"),
+        (json!({ "location": "London", "hour": 24 }).to_string(), "hour", "an integer"),
+        ("[\"London\"]".to_string(), "the arguments", "an object"),
+    ] {
+        let value = Value { json: arguments };
+        match mcp.call_call_tool(&mut store, "get_forecast", &value).expect("expected a CallToolResult") {
+            Err(ToolError::InvalidParameters(message)) => {
+                assert!(message.contains(field) && message.contains(expected), "unexpected message: {}", message);
+            }
+            other => panic!("expected InvalidParameters, got {:?}", other),
+        }
+    }
+    let value = Value { json: "{ \"location\": ".to_string() };
+    let tool_result = mcp.call_call_tool(&mut store, "get_forecast", &value).expect("expected a CallToolResult");
+    assert!(matches!(tool_result, Err(ToolError::InvalidParameters(_))));
+
+    // Test the 'get_history' tool over a two day range
+    assert_eq!(tools[2].name, "get_history");
+    let value = arguments(&tools, "get_history", json!({ "location": "London", "dt": utc_date(-3), "end_dt": utc_date(-2) }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_history", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let history = structured_content(&tools, "get_history", &call_tool_result);
+    let days = history["forecastday"].as_array().expect("forecastday is not an array");
+    assert_eq!(days.len(), 2);
+    assert_eq!(days[0]["date"], utc_date(-3));
+    assert_eq!(days[1]["date"], utc_date(-2));
+
+    let value = Value {
+        json: json!({ "location": "London", "dt": utc_date(-2), "end_dt": utc_date(-3) }).to_string(),
+    };
+    let tool_result = mcp.call_call_tool(&mut store, "get_history", &value).expect("expected a CallToolResult");
+    assert!(matches!(tool_result, Err(ToolError::InvalidParameters(_))));
+
+
+    // Test the 'get_marine' tool
+    assert_eq!(tools[3].name, "get_marine");
+    let output_schema: serde_json::Value = serde_json::from_str(&tools[3].output_schema.as_ref().expect("get_marine has no output schema").json)
+        .expect("output schema is not json");
+    assert_eq!(output_schema["title"], "Marine");
+    let value = arguments(&tools, "get_marine", json!({ "location": "Brighton", "days": 2 }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_marine", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let marine = structured_content(&tools, "get_marine", &call_tool_result);
+    let days = marine["forecastday"].as_array().expect("forecastday is not an array");
+    assert_eq!(days.len(), 2);
+    assert!(days[0]["hour"][0]["swell_ht_mt"].is_number());
+    assert!(days[0]["tides"].is_array());
+
+    // Test the 'get_astronomy' tool
+    assert_eq!(tools[4].name, "get_astronomy");
+    let value = arguments(&tools, "get_astronomy", json!({ "location": "Paris", "dt": utc_date(0) }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_astronomy", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let astronomy = structured_content(&tools, "get_astronomy", &call_tool_result);
+    assert!(astronomy["astro"]["sunrise"].is_string());
+    assert!(astronomy["astro"]["moon_phase"].is_string());
+    assert!(astronomy["astro"]["is_sun_up"].is_number());
+
+    // Test the 'search_locations' tool
+    assert_eq!(tools[5].name, "search_locations");
+    let value = arguments(&tools, "search_locations", json!({ "query": "Springfield" }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "search_locations", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let search = structured_content(&tools, "search_locations", &call_tool_result);
+    let locations = search["locations"].as_array().expect("locations is not an array");
+    assert!(locations.len() > 1);
+    let id = locations[0]["id"].as_i64().expect("location has no id");
+    assert_eq!(locations[0]["location"], format!("id:{}", id));
+
+    // The returned id selects exactly that place in the other tools
+    let value = arguments(&tools, "get_weather", json!({ "location": locations[0]["location"] }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_weather", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    match &call_tool_result.content[0] {
+        Content::Text(text_content) => assert!(text_content.text.contains("Springfield")),
+        _ => panic!("Not right content"),
+    }
+
+    // An ambiguous name lists the candidates instead of silently picking one
+    let value = arguments(&tools, "get_weather", json!({ "location": "Springfield", "disambiguate": true }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_weather", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    assert_eq!(call_tool_result.is_error, Some(false));
+    match &call_tool_result.content[0] {
+        Content::Text(text_content) => {
+            assert!(text_content.text.contains("Ask which one is meant"));
+            assert!(text_content.text.contains("location=id:"));
+        }
+        _ => panic!("Not right content"),
+    }
 
+    // Test the 'lookup_ip' tool
+    assert_eq!(tools[6].name, "lookup_ip");
+    let value = arguments(&tools, "lookup_ip", json!({ "ip": "8.8.8.8" }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "lookup_ip", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let lookup = structured_content(&tools, "lookup_ip", &call_tool_result);
+    assert_eq!(lookup["ip"], "8.8.8.8");
+    assert!(lookup["tz_id"].is_string());
+
+    // Test the 'get_timezone' tool
+    assert_eq!(tools[7].name, "get_timezone");
+    let value = arguments(&tools, "get_timezone", json!({ "location": "Tokyo" }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_timezone", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let timezone = structured_content(&tools, "get_timezone", &call_tool_result);
+    assert_eq!(timezone["tz_id"], "Asia/Tokyo");
+    assert!(timezone["localtime"].is_string());
+
+    // Test the 'get_alerts' tool
+    assert_eq!(tools[8].name, "get_alerts");
+    let value = arguments(&tools, "get_alerts", json!({ "location": "Miami" }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_alerts", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let alerts = structured_content(&tools, "get_alerts", &call_tool_result);
+    for alert in alerts["alerts"].as_array().expect("alerts is not an array") {
+        assert!(alert.get("severity").is_some());
+        assert!(alert.get("expires").is_some());
+    }
+
+    // Test the 'get_air_quality' tool
+    assert_eq!(tools[9].name, "get_air_quality");
+    let value = arguments(&tools, "get_air_quality", json!({ "location": "Delhi", "days": 2 }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_air_quality", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let air_quality = structured_content(&tools, "get_air_quality", &call_tool_result);
+    assert!(air_quality["current"]["pm2_5"].is_number());
+    assert!(air_quality["current"]["us-epa-index"].is_number());
+    assert_eq!(air_quality["forecastday"].as_array().expect("forecastday is not an array").len(), 2);
+
+    // Test the 'get_sports_events' tool
+    assert_eq!(tools[10].name, "get_sports_events");
+    let value = arguments(&tools, "get_sports_events", json!({ "location": "London" }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_sports_events", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let events = structured_content(&tools, "get_sports_events", &call_tool_result);
+    assert!(events["football"].is_array());
+    assert!(events["cricket"].is_array());
+    assert!(events["golf"].is_array());
+
+    // Test the 'get_future_weather' tool
+    assert_eq!(tools[11].name, "get_future_weather");
+    let value = arguments(&tools, "get_future_weather", json!({ "location": "Paris", "dt": utc_date(60) }));
+    let call_tool_result = mcp.call_call_tool(&mut store, "get_future_weather", &value)
+        .expect("expected a CallToolResult")
+        .expect("within another result");
+    let future = structured_content(&tools, "get_future_weather", &call_tool_result);
+    assert_eq!(future["forecastday"][0]["date"], utc_date(60));
+
+    // Dates outside the 14 to 300 days window are rejected before calling the api
+    for dt in [utc_date(3), utc_date(301)] {
+        let value = Value {
+            json: json!({ "location": "Paris", "dt": dt }).to_string(),
+        };
+        let tool_result = mcp.call_call_tool(&mut store, "get_future_weather", &value).expect("expected a CallToolResult");
+        assert!(matches!(tool_result, Err(ToolError::InvalidParameters(_))));
+    }
 
     // Test the 'list-resources' function
     let resources = mcp.call_list_resources(&mut store).unwrap();
-    assert_eq!(resources.len(), 1); // Assuming only 1 resource is available
+    assert_eq!(resources.len(), 2);
     let resource = resources[0].clone();
     assert_eq!(resource.name, "WeatherDataResource");
+    assert_eq!(resources[1].uri, "weather-api://diagnostics");
 
     
 
     // Test the 'read-resource' function
     let read_result = mcp.call_read_resource(&mut store, "weather-data-uri").unwrap();
     assert!(!read_result.unwrap().contents.is_empty());
+    let read_result = mcp.call_read_resource(&mut store, "weather-api://diagnostics").unwrap().expect("no diagnostics");
+    let diagnostics: serde_json::Value = match &read_result.contents[0] {
+        ResourceContents::Text(text) => serde_json::from_str(&text.text).expect("diagnostics are not json"),
+        _ => panic!("Not right content"),
+    };
+    assert_eq!(diagnostics["state"], "closed");
 
     // Test the 'list-prompts' function
     let prompts = mcp.call_list_prompts(&mut store).unwrap();
@@ -206,3 +415,46 @@ fn test_weather_api_router() {
 
     
 }
+
+/// Built with `--features keyvalue`, the responses one instance cached are served to the next instance.
+#[cfg(feature = "keyvalue")]
+#[test]
+fn test_cache_outlives_the_instance() {
+    dotenv().ok();
+    let file = "target/wasm32-wasip2/debug/mcp_weather_api.wasm";
+    let mut config = Config::default();
+    config.async_support(false);
+
+    let weather_api_key = env::var("WEATHER_API_KEY").expect("WEATHER_API_KEY not set in .env");
+    let weather_api_key = Box::leak(weather_api_key.into_boxed_str());
+    let engine = Engine::new(&config).unwrap();
+    let component = Component::from_file(&engine, file).unwrap();
+    let mut linker = Linker::new(&engine);
+    wasmtime_wasi::add_to_linker_sync(&mut linker).expect("wasi linker not added");
+    wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker).expect("Could not add http to linker");
+    mcp::secrets_store::add_to_linker(&mut linker,  |state: &mut MyState| state).expect("Could not link secrets store");
+    wasi::logging::logging::add_to_linker(&mut linker, |state: &mut MyState| state).expect("Could not link logging");
+    wasi::keyvalue::store::add_to_linker(&mut linker, |state: &mut MyState| &mut state.key_value_store).expect("Could not link keyvalue");
+
+    let key_value_store = KeyValueStore::default();
+    let value = Value { json: json!({ "location": "London" }).to_string() };
+    for served_from_the_cache in [false, true] {
+        let state = MyState {
+            secrets_store: SecretsStore { weather_api_key },
+            logging: Logging,
+            key_value_store: key_value_store.clone(),
+            ctx: WasiCtxBuilder::new().build(),
+            http: WasiHttpCtx::new(),
+            table: ResourceTable::new(),
+        };
+        let mut store = Store::new(&engine, state);
+        let router = McpSecretsKeyvalue::instantiate(&mut store, &component, &linker).expect("Could not instantiate");
+        let result = router.wasix_mcp_router().call_call_tool(&mut store, "get_weather", &value)
+            .expect("expected a CallToolResult")
+            .expect("get_weather failed");
+        let cache_note = result.content.iter()
+            .any(|content| matches!(content, Content::Text(text_content) if text_content.text.starts_with("Served from the cache")));
+        assert_eq!(cache_note, served_from_the_cache);
+    }
+    assert!(key_value_store.values.lock().unwrap().keys().any(|key| key.starts_with("weather-api/")));
+}